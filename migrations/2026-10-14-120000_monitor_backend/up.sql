@@ -0,0 +1 @@
+ALTER TABLE monitors ADD COLUMN backend character varying NOT NULL DEFAULT 'email';
@@ -0,0 +1 @@
+ALTER TABLE nodes ADD COLUMN pending_since timestamp with time zone;
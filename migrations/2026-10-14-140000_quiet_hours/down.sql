@@ -0,0 +1,6 @@
+DROP TABLE queued_notifications;
+
+ALTER TABLE monitors
+  DROP COLUMN quiet_start,
+  DROP COLUMN quiet_end,
+  DROP COLUMN timezone;
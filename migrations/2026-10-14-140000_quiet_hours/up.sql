@@ -0,0 +1,14 @@
+ALTER TABLE monitors
+  ADD COLUMN quiet_start time,
+  ADD COLUMN quiet_end time,
+  ADD COLUMN timezone character varying;
+
+CREATE TABLE queued_notifications
+(
+  id character varying NOT NULL,
+  email character varying NOT NULL,
+  online boolean NOT NULL,
+  deliver_at timestamp with time zone NOT NULL
+);
+ALTER TABLE queued_notifications ADD PRIMARY KEY (id, email);
+ALTER TABLE queued_notifications ADD FOREIGN KEY (id, email) REFERENCES monitors ON DELETE CASCADE;
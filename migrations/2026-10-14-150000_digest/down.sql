@@ -0,0 +1,3 @@
+DROP TABLE digest_entries;
+
+ALTER TABLE monitors DROP COLUMN notify_mode;
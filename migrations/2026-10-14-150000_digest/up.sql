@@ -0,0 +1,13 @@
+ALTER TABLE monitors ADD COLUMN notify_mode character varying NOT NULL DEFAULT 'immediate';
+
+CREATE TABLE digest_entries
+(
+  seq serial PRIMARY KEY,
+  email character varying NOT NULL,
+  node_id character varying NOT NULL,
+  name character varying NOT NULL,
+  online boolean NOT NULL,
+  at timestamp with time zone NOT NULL,
+  deliver_at timestamp with time zone NOT NULL,
+  FOREIGN KEY (node_id, email) REFERENCES monitors ON DELETE CASCADE
+);
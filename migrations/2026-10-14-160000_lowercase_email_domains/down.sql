@@ -0,0 +1 @@
+-- The original case of the domains is lost, so there is nothing to undo.
@@ -0,0 +1,22 @@
+-- Email domains are now stored in lower case.  Normalize existing addresses; where that makes
+-- two monitors identical, keep only one of them, preferring the one that is already normalized.
+CREATE FUNCTION pg_temp.normalize_email(email character varying) RETURNS character varying AS $$
+  SELECT split_part(email, '@', 1) || '@' || lower(split_part(email, '@', 2))
+$$ LANGUAGE sql IMMUTABLE;
+
+DELETE FROM monitors m USING monitors o
+  WHERE m.id = o.id
+    AND pg_temp.normalize_email(m.email) = pg_temp.normalize_email(o.email)
+    AND m.email <> o.email
+    AND (o.email = pg_temp.normalize_email(o.email)
+      OR (m.email <> pg_temp.normalize_email(m.email) AND o.email < m.email));
+
+ALTER TABLE queued_notifications DROP CONSTRAINT queued_notifications_id_email_fkey;
+ALTER TABLE digest_entries DROP CONSTRAINT digest_entries_node_id_email_fkey;
+
+UPDATE monitors SET email = pg_temp.normalize_email(email);
+UPDATE queued_notifications SET email = pg_temp.normalize_email(email);
+UPDATE digest_entries SET email = pg_temp.normalize_email(email);
+
+ALTER TABLE queued_notifications ADD FOREIGN KEY (id, email) REFERENCES monitors ON DELETE CASCADE;
+ALTER TABLE digest_entries ADD FOREIGN KEY (node_id, email) REFERENCES monitors ON DELETE CASCADE;
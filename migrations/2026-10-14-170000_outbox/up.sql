@@ -0,0 +1,9 @@
+CREATE TABLE outbox
+(
+  seq serial PRIMARY KEY,
+  sender character varying NOT NULL,
+  recipients character varying NOT NULL,
+  message bytea NOT NULL,
+  created_at timestamp with time zone NOT NULL,
+  claimed_at timestamp with time zone
+);
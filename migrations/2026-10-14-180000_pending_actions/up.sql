@@ -0,0 +1,9 @@
+CREATE TABLE pending_actions
+(
+  email character varying NOT NULL,
+  node character varying NOT NULL,
+  node_name character varying NOT NULL,
+  action character varying NOT NULL,
+  sent_at timestamp with time zone NOT NULL,
+  PRIMARY KEY (email, node)
+);
@@ -0,0 +1 @@
+ALTER TABLE monitors ADD COLUMN lang character varying;
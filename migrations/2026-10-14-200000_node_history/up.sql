@@ -0,0 +1,8 @@
+CREATE TABLE node_history
+(
+  seq serial PRIMARY KEY,
+  node_id character varying NOT NULL,
+  online boolean NOT NULL,
+  at timestamp with time zone NOT NULL
+);
+CREATE INDEX node_history_node_at ON node_history (node_id, at);
@@ -0,0 +1,6 @@
+CREATE TABLE suppressions
+(
+  email character varying PRIMARY KEY,
+  reason character varying,
+  created_at timestamp with time zone NOT NULL
+);
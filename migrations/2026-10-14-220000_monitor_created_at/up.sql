@@ -0,0 +1 @@
+ALTER TABLE monitors ADD COLUMN created_at timestamp with time zone;
@@ -0,0 +1,8 @@
+CREATE TABLE idempotency_keys
+(
+  key character varying PRIMARY KEY,
+  request character varying NOT NULL,
+  status smallint,
+  response character varying,
+  created_at timestamp with time zone NOT NULL
+);
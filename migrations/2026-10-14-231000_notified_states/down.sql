@@ -0,0 +1 @@
+DROP TABLE notified_states;
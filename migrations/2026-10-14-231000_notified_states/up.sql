@@ -0,0 +1,9 @@
+CREATE TABLE notified_states
+(
+  node_id character varying PRIMARY KEY,
+  online boolean NOT NULL,
+  notified_at timestamp with time zone NOT NULL
+);
+-- Watchers were told about the state the nodes are in now (or did not care)
+INSERT INTO notified_states (node_id, online, notified_at)
+  SELECT id, online, now() FROM nodes;
@@ -0,0 +1,11 @@
+DELETE FROM queued_notifications WHERE monitor_id <> id;
+DELETE FROM digest_entries WHERE monitor_id <> node_id;
+DELETE FROM monitors WHERE id LIKE '%*';
+
+ALTER TABLE queued_notifications DROP CONSTRAINT queued_notifications_monitor_id_email_fkey;
+ALTER TABLE digest_entries DROP CONSTRAINT digest_entries_monitor_id_email_fkey;
+ALTER TABLE queued_notifications DROP COLUMN monitor_id;
+ALTER TABLE digest_entries DROP COLUMN monitor_id;
+
+ALTER TABLE queued_notifications ADD FOREIGN KEY (id, email) REFERENCES monitors ON DELETE CASCADE;
+ALTER TABLE digest_entries ADD FOREIGN KEY (node_id, email) REFERENCES monitors ON DELETE CASCADE;
@@ -0,0 +1,14 @@
+-- A monitor can now cover several nodes (its ID then ends in `*`), so queued notifications and
+-- digest entries remember which monitor they are for, separately from the node.
+ALTER TABLE queued_notifications DROP CONSTRAINT queued_notifications_id_email_fkey;
+ALTER TABLE digest_entries DROP CONSTRAINT digest_entries_node_id_email_fkey;
+
+ALTER TABLE queued_notifications ADD COLUMN monitor_id character varying;
+UPDATE queued_notifications SET monitor_id = id;
+ALTER TABLE queued_notifications ALTER COLUMN monitor_id SET NOT NULL;
+ALTER TABLE digest_entries ADD COLUMN monitor_id character varying;
+UPDATE digest_entries SET monitor_id = node_id;
+ALTER TABLE digest_entries ALTER COLUMN monitor_id SET NOT NULL;
+
+ALTER TABLE queued_notifications ADD FOREIGN KEY (monitor_id, email) REFERENCES monitors ON DELETE CASCADE;
+ALTER TABLE digest_entries ADD FOREIGN KEY (monitor_id, email) REFERENCES monitors ON DELETE CASCADE;
@@ -0,0 +1,2 @@
+DROP TABLE weekly_summaries;
+ALTER TABLE monitors DROP COLUMN weekly_summary;
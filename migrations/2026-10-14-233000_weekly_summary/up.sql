@@ -0,0 +1,8 @@
+ALTER TABLE monitors ADD COLUMN weekly_summary boolean NOT NULL DEFAULT false;
+
+-- When the next weekly summary is due for each address that wants one
+CREATE TABLE weekly_summaries
+(
+  email character varying PRIMARY KEY,
+  deliver_at timestamp with time zone NOT NULL
+);
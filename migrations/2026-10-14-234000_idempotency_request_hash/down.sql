@@ -0,0 +1,2 @@
+DELETE FROM idempotency_keys;
+ALTER TABLE idempotency_keys RENAME COLUMN request_hash TO request;
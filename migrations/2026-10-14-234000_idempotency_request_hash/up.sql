@@ -0,0 +1,3 @@
+-- Only keep a hash of the request, as its body contains an email address
+DELETE FROM idempotency_keys;
+ALTER TABLE idempotency_keys RENAME COLUMN request TO request_hash;
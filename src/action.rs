@@ -17,18 +17,23 @@
 use rocket::form::FromFormField;
 use rocket::FromForm;
 
-use anyhow::{bail, Result};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::Utc;
 use diesel::prelude::*;
-use diesel::result::{DatabaseErrorKind, Error as DieselError};
-use ring::{error, hmac};
-use rmp_serde::to_vec as serialize_to_vec;
+use diesel::result::Error as DieselError;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use crate::db::DbConn;
 use crate::email::EmailAddress;
 use crate::models::*;
+use crate::notify::{Backend, NotifyMode};
+use crate::outbox::OutboxMessage;
+use crate::quiet::QuietHours;
 use crate::schema::*;
+use crate::token::SignedToken;
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug, Copy, Clone, FromFormField)]
 #[repr(u8)]
@@ -37,81 +42,203 @@ pub enum Operation {
     Remove = 0,
 }
 
+/// If `node` ends in `*`, it is a pattern covering all nodes whose ID starts with what comes
+/// before.  Return that prefix.
+pub fn node_prefix(node: &str) -> Option<&str> {
+    node.strip_suffix('*')
+}
+
 #[derive(Serialize, Deserialize, FromForm, Clone)]
 pub struct Action {
     pub node: String,
     pub email: EmailAddress,
     pub op: Operation,
+    #[field(default_with = Some(Backend::Email))]
+    pub backend: Backend,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    #[serde(default)]
+    #[field(default_with = Some(NotifyMode::Immediate))]
+    pub notify_mode: NotifyMode,
+    /// The language for the emails; `None` means the default language
+    #[serde(default)]
+    pub lang: Option<String>,
+    /// Include the node in the weekly summary of how often the watched nodes were online
+    #[serde(default)]
+    pub weekly_summary: bool,
+    /// Add the node even though it is not in the node list, e.g. because it is offline and
+    /// dropped out of the list.  This is only checked when asking for confirmation, so it is not
+    /// part of the signed action.
+    #[serde(skip)]
+    pub force: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct SignedAction {
-    action: Action,
-    signature: Box<[u8]>,
-}
+pub type SignedAction = SignedToken<Action>;
 
 impl Action {
-    fn compute_signature(&self, key: &hmac::Key) -> hmac::Tag {
-        let buf = serialize_to_vec(self).expect("failed to encode Action");
-        hmac::sign(key, buf.as_slice())
-    }
-
-    fn verify_signature(
-        &self,
-        key: &hmac::Key,
-        signature: &[u8],
-    ) -> Result<(), error::Unspecified> {
-        let buf = serialize_to_vec(self).expect("failed to encode Action");
-        hmac::verify(key, buf.as_slice(), signature)
+    /// The monitor this action adds (or removes)
+    fn monitor(&self) -> Monitor<'_> {
+        Monitor {
+            id: self.node.as_str(),
+            email: &self.email,
+            backend: self.backend.as_str(),
+            quiet_start: self.quiet_hours.map(|quiet_hours| quiet_hours.start.0),
+            quiet_end: self.quiet_hours.map(|quiet_hours| quiet_hours.end.0),
+            timezone: self
+                .quiet_hours
+                .map(|quiet_hours| quiet_hours.timezone.0.name()),
+            notify_mode: self.notify_mode.as_str(),
+            lang: self.lang.as_deref(),
+            created_at: Utc::now(),
+            weekly_summary: self.weekly_summary,
+        }
     }
 
-    pub fn sign(self, key: &hmac::Key) -> SignedAction {
-        let signature = self.compute_signature(key);
-        let signature = signature.as_ref().to_vec().into_boxed_slice();
-        SignedAction {
-            action: self,
-            signature,
-        }
+    /// Whether adding the node would leave `monitor`, the one that exists already, as it is
+    pub fn has_settings_of(&self, monitor: &MonitorQuery) -> bool {
+        let m = self.monitor();
+        m.backend == monitor.backend
+            && m.quiet_start == monitor.quiet_start
+            && m.quiet_end == monitor.quiet_end
+            && m.timezone == monitor.timezone.as_deref()
+            && m.notify_mode == monitor.notify_mode
+            && m.lang == monitor.lang.as_deref()
+            && m.weekly_summary == monitor.weekly_summary
     }
 
     pub async fn run(&self, db: &DbConn) -> Result<bool> {
-        let op = self.op;
-        let node = self.node.clone();
-        let email = self.email.clone();
+        let action = self.clone();
         db.run(move |db| {
-            let m = Monitor {
-                id: node.as_str(),
-                email: &email,
-            };
-            Ok(match op {
-                Operation::Add => {
-                    // Add node.  We are fine if it does not exist.
-                    let r = diesel::insert_into(monitors::table).values(&m).execute(db);
-                    // Handle UniqueViolation gracefully
-                    match r {
-                        Ok(_) => true,
-                        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
-                            false
+            db.transaction::<_, DieselError, _>(|db| {
+                let m = action.monitor();
+                let (node, email) = (action.node.as_str(), &*action.email);
+                let success = match action.op {
+                    Operation::Add => {
+                        // Add node.  We are fine if it does not exist.  If it is monitored
+                        // already, this changes its settings to those of the action.
+                        let existing = monitors::table
+                            .find((node, email))
+                            .for_update()
+                            .first::<MonitorQuery>(db)
+                            .optional()?;
+                        match existing {
+                            Some(monitor) if action.has_settings_of(&monitor) => false,
+                            _ => {
+                                use diesel::upsert::excluded;
+                                diesel::insert_into(monitors::table)
+                                    .values(&m)
+                                    .on_conflict((monitors::id, monitors::email))
+                                    .do_update()
+                                    .set((
+                                        monitors::backend.eq(excluded(monitors::backend)),
+                                        monitors::quiet_start.eq(excluded(monitors::quiet_start)),
+                                        monitors::quiet_end.eq(excluded(monitors::quiet_end)),
+                                        monitors::timezone.eq(excluded(monitors::timezone)),
+                                        monitors::notify_mode.eq(excluded(monitors::notify_mode)),
+                                        monitors::lang.eq(excluded(monitors::lang)),
+                                        monitors::weekly_summary
+                                            .eq(excluded(monitors::weekly_summary)),
+                                    ))
+                                    .execute(db)?;
+                                true
+                            }
                         }
-                        Err(e) => bail!(e),
                     }
-                }
-                Operation::Remove => {
-                    let num_deleted = diesel::delete(&m).execute(db)?;
-                    num_deleted > 0
-                }
+                    Operation::Remove => {
+                        let num_deleted = diesel::delete(&m).execute(db)?;
+                        num_deleted > 0
+                    }
+                };
+                // The action is no longer waiting for confirmation
+                diesel::delete(pending_actions::table.find((email, node))).execute(db)?;
+                Ok(success)
             })
         })
         .await
+        .map_err(Into::into)
     }
-}
 
-impl SignedAction {
-    pub fn verify(self, key: &hmac::Key) -> Result<Action, error::Unspecified> {
-        // Using a match to make it really clear we don't return the action in case of failure
-        match self.action.verify_signature(key, &self.signature) {
-            Ok(_) => Ok(self.action),
-            Err(e) => Err(e),
-        }
+    /// Remember that a confirmation email is sent for this action, so that it can be resent, and
+    /// in the same transaction put that email (if any) into the outbox.
+    pub async fn remember_pending(
+        &self,
+        db: &DbConn,
+        node_name: String,
+        confirmation: Option<OutboxMessage>,
+    ) -> Result<()> {
+        let action = serde_json::to_string(self)?;
+        let email = self.email.clone();
+        let node = self.node.clone();
+        db.run(move |db| {
+            db.transaction::<_, DieselError, _>(|db| {
+                let pending = PendingAction {
+                    email: &email,
+                    node: &node,
+                    node_name: &node_name,
+                    action: &action,
+                    sent_at: Utc::now(),
+                };
+                diesel::insert_into(pending_actions::table)
+                    .values(&pending)
+                    .on_conflict((pending_actions::email, pending_actions::node))
+                    .do_update()
+                    .set(&pending)
+                    .execute(db)?;
+                if let Some(email) = confirmation {
+                    email.enqueue(db)?;
+                }
+                Ok(())
+            })
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Return the actions (with the names of their nodes) that still wait for confirmation by
+    /// `email`.
+    pub async fn pending(db: &DbConn, email: EmailAddress) -> Result<Vec<(Action, String)>> {
+        let pending = db
+            .run(move |db| {
+                pending_actions::table
+                    .filter(pending_actions::email.eq(&*email))
+                    .load::<PendingActionQuery>(db)
+            })
+            .await?;
+        pending
+            .into_iter()
+            .map(|p| Ok((serde_json::from_str(&p.action)?, p.node_name)))
+            .collect()
+    }
+
+    /// Mark the actions that still wait for confirmation by `email` as sent again, and in the same
+    /// transaction put their `confirmations` into the outbox.  Does nothing and returns `false` if
+    /// the last confirmation email was sent less than `cooldown` ago.
+    pub async fn resend_pending(
+        db: &DbConn,
+        email: EmailAddress,
+        cooldown: Duration,
+        confirmations: Vec<OutboxMessage>,
+    ) -> Result<bool> {
+        let now = Utc::now();
+        let cooldown_start = now - chrono::Duration::from_std(cooldown)?;
+        let resent = db
+            .run(move |db| {
+                db.transaction::<_, DieselError, _>(|db| {
+                    let mine = pending_actions::table.filter(pending_actions::email.eq(&*email));
+                    let pending = mine.for_update().load::<PendingActionQuery>(db)?;
+                    if pending.iter().any(|p| p.sent_at > cooldown_start) {
+                        return Ok(false);
+                    }
+                    diesel::update(mine)
+                        .set(pending_actions::sent_at.eq(now))
+                        .execute(db)?;
+                    for confirmation in confirmations {
+                        confirmation.enqueue(db)?;
+                    }
+                    Ok(true)
+                })
+            })
+            .await?;
+        Ok(resent)
     }
 }
@@ -0,0 +1,56 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use ring::constant_time;
+use rocket::{
+    http::Status,
+    request::{self, FromRequest, Outcome},
+    Request,
+};
+
+use crate::config::Config;
+
+/// A request guard for routes that only the administrator may use.  The request must carry the
+/// configured `admin_token` in an `Authorization: Bearer ...` header.  If no token is configured,
+/// these routes do not exist.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = request.rocket().state::<Config>().unwrap();
+        let Some(admin_token) = &config.secrets.admin_token else {
+            return Outcome::Error((Status::NotFound, ()));
+        };
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|auth| auth.strip_prefix("Bearer "));
+        match token {
+            Some(token)
+                if constant_time::verify_slices_are_equal(
+                    token.as_bytes(),
+                    admin_token.as_bytes(),
+                )
+                .is_ok() =>
+            {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Command-line tools
+
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context as _, Result};
+use chrono::Utc;
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use serde_json::json;
+
+use crate::config;
+use crate::notify::{Direction, NodeDetails, NodeEvent, Watcher};
+use crate::util::Ctx;
+
+const USAGE: &str =
+    "usage: ff-node-monitor send-test --to <address> [--template <name>] [--lang <lang>]";
+
+/// The templates `send-test` has sample data for
+const TEMPLATES: &[&str] = &[
+    "test_email",
+    "notification",
+    "node_down",
+    "node_up",
+    "notifications",
+];
+
+struct SendTest {
+    to: String,
+    template: String,
+    lang: Option<String>,
+}
+
+impl SendTest {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut to = None;
+        let mut template = "test_email".to_owned();
+        let mut lang = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .with_context(|| format!("`{}` needs a value", arg))
+            };
+            match arg.as_str() {
+                "--to" => to = Some(value()?),
+                "--template" => template = value()?,
+                "--lang" => lang = Some(value()?),
+                _ => bail!("unknown argument `{}`", arg),
+            }
+        }
+        let Some(to) = to else {
+            bail!("`--to` is missing");
+        };
+        if !TEMPLATES.contains(&template.as_str()) {
+            bail!(
+                "there is no sample data for template `{}`; available are: {}",
+                template,
+                TEMPLATES.join(", ")
+            );
+        }
+        Ok(SendTest { to, template, lang })
+    }
+
+    /// Render the template with sample data and send it through the configured transport
+    async fn run(&self, ctx: &Ctx<'_>) -> Result<()> {
+        let watcher = Watcher {
+            email: self.to.clone(),
+            lang: self.lang.clone(),
+            pattern: None,
+        };
+        let (vals, unsubscribe_url) = match self.template.as_str() {
+            "test_email" => (json!({}), None),
+            "notifications" => {
+                let events = [
+                    sample_event("c04a00dd692a", "Beispielknoten", Direction::Down),
+                    sample_event("c04a00dd692b", "Nachbarknoten", Direction::Down),
+                ];
+                let events: Vec<_> = events.iter().collect();
+                let (vals, unsubscribe_all_url) =
+                    ctx.combined_notification_vals(&events, &watcher)?;
+                (vals, Some(unsubscribe_all_url))
+            }
+            template => {
+                let direction = if template == "node_up" {
+                    Direction::Up
+                } else {
+                    Direction::Down
+                };
+                let event = sample_event("c04a00dd692a", "Beispielknoten", direction);
+                let (vals, unsubscribe_url) = ctx.notification_vals(&event, &watcher)?;
+                (vals, Some(unsubscribe_url))
+            }
+        };
+        // Like real notifications, fall back to the generic template if there is no
+        // direction-specific one.
+        let template = match self.template.as_str() {
+            "node_up" | "node_down"
+                if ctx
+                    .find_template(&self.template, self.lang.as_deref())
+                    .is_none() =>
+            {
+                "notification"
+            }
+            template => template,
+        };
+        let message = ctx.build_email(
+            template,
+            self.lang.as_deref(),
+            vals,
+            &self.to,
+            unsubscribe_url.as_deref(),
+        )?;
+        ctx.mailer()?.send(message).await
+    }
+}
+
+fn sample_event(node_id: &str, name: &str, direction: Direction) -> NodeEvent {
+    NodeEvent {
+        node_id: node_id.to_owned(),
+        name: name.to_owned(),
+        from: direction.old_state(),
+        to: direction.new_state(),
+        at: Utc::now(),
+        details: NodeDetails {
+            last_seen: Some(Utc::now()),
+            uptime_secs: Some(3.0 * 24.0 * 60.0 * 60.0 + 4.0 * 60.0 * 60.0),
+            firmware: Some("v2.0.0".to_owned()),
+            contact: Some("betreiber@example.org".to_owned()),
+        },
+    }
+}
+
+/// `send-test`: send one email rendered from sample data, then exit.  This starts the
+/// application without its routes and background jobs, on a random local port, since emails can
+/// only be built by a running application.
+pub async fn send_test(figment: Figment, args: &[String]) {
+    let send_test = match SendTest::parse(args) {
+        Ok(send_test) => send_test,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let result = Arc::new(Mutex::new(None));
+    let figment = figment
+        .merge(("address", "127.0.0.1"))
+        .merge(("port", 0))
+        .merge(("log_level", "critical"));
+    let rocket = crate::base(figment).attach(AdHoc::on_liftoff("Send test email", {
+        let result = result.clone();
+        move |rocket| {
+            Box::pin(async move {
+                let outcome = send_test.run(&Ctx::new(rocket)).await;
+                *result.lock().unwrap() = Some(outcome);
+                rocket.shutdown().notify();
+            })
+        }
+    }));
+    if let Err(e) = rocket.launch().await {
+        eprintln!("failed to start: {}", e);
+        process::exit(1);
+    }
+    let outcome = result.lock().unwrap().take();
+    match outcome {
+        Some(Ok(())) => println!("email sent"),
+        Some(Err(e)) => {
+            eprintln!("failed to send email: {:#}", e);
+            process::exit(1);
+        }
+        None => {
+            eprintln!("failed to start, see the configuration errors above");
+            process::exit(1);
+        }
+    }
+}
+
+/// `--check-config [<path>]`: load and validate the configuration like the service does on
+/// startup, then exit.  Without a path, the configuration is found like on startup.
+pub fn check_config(args: &[String]) {
+    match args {
+        [] => {}
+        [path] => {
+            if let Err(e) = std::fs::metadata(path) {
+                eprintln!("cannot read {}: {}", path, e);
+                process::exit(1);
+            }
+            // This is where `config::figment` looks for the file
+            std::env::set_var("ROCKET_CONFIG", path);
+        }
+        _ => {
+            eprintln!("usage: ff-node-monitor --check-config [<path>]");
+            process::exit(2);
+        }
+    }
+    match config::figment().and_then(|figment| config::load(&figment, crate::CONFIG_SECTION)) {
+        Ok(_) => println!("configuration is valid"),
+        Err(e) => {
+            eprintln!("{:#}", e);
+            process::exit(1);
+        }
+    }
+}
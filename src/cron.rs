@@ -14,45 +14,82 @@
 //  You should have received a copy of the GNU Affero General Public License
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
+use chrono::{DateTime, Datelike as _, Days, NaiveTime, TimeZone as _, Utc};
+use diesel::dsl::not;
 use diesel::prelude::*;
-use serde_json::{self, json};
+use diesel::upsert::excluded;
+use serde_json::json;
 
-use rocket::uri;
+use reqwest::header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use ring::rand::{SecureRandom, SystemRandom};
+use rocket::fairing::{AdHoc, Fairing};
+use rocket::{tokio, uri};
+use url::Url;
 
+use crate::action::node_prefix;
+use crate::config::Config;
 use crate::db::DbConn;
 use crate::email::EmailAddress;
 use crate::models;
+use crate::notify::{
+    Backend, Direction, EmailNotifier, MatrixNotifier, NodeDetails, NodeEvent, NodeState, Notifier,
+    NotifyMode, Watcher, WebhookNotifier,
+};
+use crate::quiet::QuietHours;
 use crate::routes;
 use crate::schema::*;
-use crate::util::Ctx;
+use crate::upstream;
+use crate::util::{self, Ctx};
 
 mod json {
     use chrono::{DateTime, Utc};
     use serde::Deserialize;
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, Clone)]
     pub(crate) struct NodeInfo {
         pub(crate) node_id: Option<String>,
         pub(crate) hostname: Option<String>,
+        pub(crate) owner: Option<Owner>,
+        pub(crate) software: Option<Software>,
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, Clone)]
+    pub(crate) struct Owner {
+        pub(crate) contact: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug, Clone)]
+    pub(crate) struct Software {
+        pub(crate) firmware: Option<Firmware>,
+    }
+
+    #[derive(Deserialize, Debug, Clone)]
+    pub(crate) struct Firmware {
+        pub(crate) release: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug, Clone)]
     pub(crate) struct Flags {
         pub(crate) online: bool,
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, Clone)]
     #[allow(unused)] // we don't actually need all these fields
     pub(crate) struct Statistics {
         pub(crate) memory_usage: Option<f64>,
         pub(crate) rootfs_usage: Option<f64>,
         pub(crate) loadavg: Option<f64>,
+        pub(crate) uptime: Option<f64>,
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, Clone)]
     #[allow(unused)] // we don't actually need all these fields
     pub(crate) struct Node {
         pub(crate) nodeinfo: NodeInfo,
@@ -62,7 +99,7 @@ mod json {
         pub(crate) firstseen: DateTime<Utc>,
     }
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, Clone)]
     #[allow(unused)] // we don't actually need all these fields
     pub(crate) struct Nodes {
         pub(crate) version: usize,
@@ -72,17 +109,30 @@ mod json {
 }
 
 // Just the data about the node (the RHS of the HashMap)
-#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[derive(Clone)]
 struct NodeData {
     name: String,
-    online: bool,
+    state: NodeState,
+    /// Only known for nodes from the node list, not for those from the database
+    details: NodeDetails,
 }
 
 // From a JSON node, extract node ID and other information
 fn json_to_node_data(node: json::Node) -> Option<(String, NodeData)> {
+    let details = NodeDetails {
+        last_seen: Some(node.lastseen),
+        uptime_secs: node.statistics.uptime,
+        firmware: node
+            .nodeinfo
+            .software
+            .and_then(|software| software.firmware)
+            .and_then(|firmware| firmware.release),
+        contact: node.nodeinfo.owner.and_then(|owner| owner.contact),
+    };
     let node_data = NodeData {
         name: node.nodeinfo.hostname?,
-        online: node.flags.online,
+        state: NodeState::from_online(node.flags.online),
+        details,
     };
     Some((node.nodeinfo.node_id?, node_data))
 }
@@ -90,128 +140,902 @@ fn json_to_node_data(node: json::Node) -> Option<(String, NodeData)> {
 fn model_to_node_data(node: models::NodeQuery) -> (String, NodeData) {
     let node_data = NodeData {
         name: node.name,
-        online: node.online,
+        state: NodeState::from_online(node.online),
+        details: NodeDetails::default(),
     };
     (node.id, node_data)
 }
 
 impl NodeData {
-    fn into_model(self, id: String) -> models::NodeQuery {
-        models::NodeQuery {
-            id,
+    fn into_event(self, id: String, direction: Direction, at: DateTime<Utc>) -> NodeEvent {
+        NodeEvent {
+            node_id: id,
             name: self.name,
-            online: self.online,
+            from: direction.old_state(),
+            to: direction.new_state(),
+            at,
+            details: self.details,
+        }
+    }
+}
+
+/// What to change about a node in the database
+#[derive(PartialEq, Debug)]
+enum NodeChange {
+    /// Set the name and since when a state change is pending, and the state if given
+    Update {
+        id: String,
+        name: String,
+        online: Option<bool>,
+        pending_since: Option<DateTime<Utc>>,
+    },
+    Insert {
+        id: String,
+        name: String,
+        online: bool,
+        pending_since: Option<DateTime<Utc>>,
+    },
+    Delete(String),
+}
+
+/// Compare the nodes in the database with the current node lists.  Returns the state changes to
+/// report, and how to bring the database up to date.  A state change only counts once it lasted
+/// for `flap_suppress`; until then, we remember since when the change is pending.  Nodes for
+/// which `skip` holds are left alone.
+///
+/// A node missing from the node lists counts as offline, so a node that disappears while online
+/// goes down, and a new node that is online comes up; a new offline node, or an offline node
+/// that disappears, is no event.  A node that stays online or offline is no event either, but its
+/// name is updated.
+fn diff_nodes(
+    db_nodes: Vec<models::NodeQuery>,
+    mut cur_nodes: HashMap<String, NodeData>,
+    skip: impl Fn(&str) -> bool,
+    flap_suppress: chrono::Duration,
+    now: DateTime<Utc>,
+) -> (Vec<NodeEvent>, Vec<NodeChange>) {
+    let mut events = Vec::new();
+    let mut changes = Vec::new();
+
+    // Go over every node in the database
+    for db_node in db_nodes.into_iter() {
+        if skip(&db_node.id) {
+            continue;
+        }
+        let pending_since = db_node.pending_since;
+        let (id, db_data) = model_to_node_data(db_node);
+        if let Some(cur_data) = cur_nodes.remove(&id) {
+            // We already know this node.
+            // Did its online status change?
+            if let Some(direction) = NodeState::transition(db_data.state, cur_data.state) {
+                let pending_since = pending_since.unwrap_or(now);
+                if now - pending_since >= flap_suppress {
+                    // The new state is stable, make it official
+                    changes.push(NodeChange::Update {
+                        id: id.clone(),
+                        name: cur_data.name.clone(),
+                        online: Some(cur_data.state.is_online()),
+                        pending_since: None,
+                    });
+                    events.push(cur_data.into_event(id, direction, now));
+                } else {
+                    // Wait and see if the node settles in its new state
+                    changes.push(NodeChange::Update {
+                        id,
+                        name: cur_data.name,
+                        online: None,
+                        pending_since: Some(pending_since),
+                    });
+                }
+            } else if cur_data.name != db_data.name || pending_since.is_some() {
+                // Update name in database, and forget about any state change that did not last
+                changes.push(NodeChange::Update {
+                    id,
+                    name: cur_data.name,
+                    online: None,
+                    pending_since: None,
+                });
+            }
+        } else if let Some(direction) = NodeState::transition(db_data.state, NodeState::Offline) {
+            // The node was online but does not exist any more, so this is a change to offline.
+            let pending_since = pending_since.unwrap_or(now);
+            if now - pending_since >= flap_suppress {
+                changes.push(NodeChange::Delete(id.clone()));
+                let data = NodeData {
+                    state: NodeState::Offline,
+                    ..db_data
+                };
+                events.push(data.into_event(id, direction, now));
+            } else {
+                // Keep the node around until we know it is gone for good
+                changes.push(NodeChange::Update {
+                    id,
+                    name: db_data.name,
+                    online: None,
+                    pending_since: Some(pending_since),
+                });
+            }
+        } else {
+            // The node is in the DB but does not exist any more.
+            changes.push(NodeChange::Delete(id));
         }
     }
+
+    // Go over nodes remaining in the hash map -- they are not in the DB
+    for (id, cur_data) in cur_nodes.into_iter() {
+        // A node that did not exist is implicitly offline, so it appearing online is a change.
+        let direction = NodeState::transition(NodeState::Offline, cur_data.state);
+        // Unless we notify immediately, record the node as offline until it has been online for
+        // long enough.
+        let settled = direction.is_none() || flap_suppress.is_zero();
+        changes.push(NodeChange::Insert {
+            id: id.clone(),
+            name: cur_data.name.clone(),
+            online: settled && cur_data.state.is_online(),
+            pending_since: if settled { None } else { Some(now) },
+        });
+        if let (Some(direction), true) = (direction, settled) {
+            events.push(cur_data.into_event(id, direction, now));
+        }
+    }
+
+    (events, changes)
+}
+
+/// Keep only the events about a change to a state other than the one we notified about last
+/// (`true` for online), so that a node that went down is reported once until it recovered, even
+/// if we lost track of it in between
+fn not_notified(last_notified: &HashMap<String, bool>, events: Vec<NodeEvent>) -> Vec<NodeEvent> {
+    events
+        .into_iter()
+        .filter(|event| last_notified.get(&event.node_id) != Some(&event.to.is_online()))
+        .collect()
+}
+
+fn apply_node_change(db: &mut PgConnection, change: NodeChange) -> QueryResult<()> {
+    match change {
+        NodeChange::Update {
+            id,
+            name,
+            online: Some(online),
+            pending_since,
+        } => diesel::update(nodes::table.find(id))
+            .set((
+                nodes::name.eq(name),
+                nodes::online.eq(online),
+                nodes::pending_since.eq(pending_since),
+            ))
+            .execute(db)?,
+        NodeChange::Update {
+            id,
+            name,
+            online: None,
+            pending_since,
+        } => diesel::update(nodes::table.find(id))
+            .set((nodes::name.eq(name), nodes::pending_since.eq(pending_since)))
+            .execute(db)?,
+        NodeChange::Insert {
+            id,
+            name,
+            online,
+            pending_since,
+        } => diesel::insert_into(nodes::table)
+            .values(&models::Node {
+                id: &id,
+                name: &name,
+                online,
+                pending_since,
+            })
+            .execute(db)?,
+        NodeChange::Delete(id) => diesel::delete(nodes::table.find(id)).execute(db)?,
+    };
+    Ok(())
+}
+
+/// Compute the time until the next poll: the interval, randomly shifted by up to
+/// `jitter_percent` percent in either direction.
+fn poll_delay(interval: Duration, jitter_percent: u8) -> Duration {
+    if jitter_percent == 0 {
+        return interval;
+    }
+    let mut bytes = [0u8; 4];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("failed to generate random number");
+    // A random factor in [-1, 1]
+    let factor = u32::from_le_bytes(bytes) as f64 / u32::MAX as f64 * 2.0 - 1.0;
+    let jitter = interval.as_secs_f64() * f64::from(jitter_percent) / 100.0 * factor;
+    Duration::from_secs_f64((interval.as_secs_f64() + jitter).max(0.0))
+}
+
+/// If configured, regularly trigger an update of the node list.
+///
+/// The background task cannot call `update_nodes` itself as that needs the running instance, so
+/// instead it requests the `/cron` route just like an external cron job would.  Rocket turns a
+/// panic in `update_nodes` into an error response there, so every request that does not succeed is
+/// reported to `error_reports` like a panic of the worker itself would be.
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_liftoff("Poll node list", |rocket| {
+        Box::pin(async move {
+            let config = rocket.state::<Config>().unwrap();
+            let Some(interval) = config.ui.get_poll_interval() else {
+                return;
+            };
+            let jitter_percent = config.ui.get_poll_jitter_percent();
+            let cron_url = util::local_url(rocket, uri!(routes::cron_route));
+            let error_reports = config.urls.error_reports.clone();
+            let root = config.urls.root.clone();
+            let shutdown = rocket.shutdown();
+            tokio::spawn(supervise(
+                POLL_WORKER,
+                error_reports.clone(),
+                root.clone(),
+                shutdown.clone(),
+                move || {
+                    let cron_url = cron_url.clone();
+                    let error_reports = error_reports.clone();
+                    let root = root.clone();
+                    let shutdown = shutdown.clone();
+                    async move {
+                        loop {
+                            tokio::select! {
+                                _ = tokio::time::sleep(poll_delay(interval, jitter_percent)) => {}
+                                _ = shutdown.clone() => break,
+                            }
+                            let message = match reqwest::get(&cron_url).await {
+                                Ok(r) if r.status().is_success() => continue,
+                                Ok(r) => format!("status {}", r.status()),
+                                Err(e) => e.to_string(),
+                            };
+                            log::error!("polling node list failed: {}", message);
+                            if let Some(url) = &error_reports {
+                                report_error(url, &root, POLL_WORKER, &message).await;
+                            }
+                        }
+                    }
+                },
+            ));
+        })
+    })
+}
+
+/// The name of the background worker polling the node list, as used in logs and error reports
+const POLL_WORKER: &str = "poll node list";
+
+/// The first pause before restarting a background worker that panicked
+const RESTART_BACKOFF_START: Duration = Duration::from_secs(1);
+/// The longest pause before restarting a background worker; a worker that ran at least this long
+/// before panicking is restarted after `RESTART_BACKOFF_START` again.
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// Run the background worker `name` built by `worker` until it returns or `shutdown` is
+/// triggered.  If it panics, the panic is logged and reported to `error_reports`, and the worker
+/// is started again after a pause that doubles with every panic in a row.  Otherwise a panic would
+/// end the worker for good, and nobody would notice until notifications stop arriving.
+async fn supervise<F, Fut>(
+    name: &'static str,
+    error_reports: Option<Url>,
+    root: Url,
+    shutdown: rocket::Shutdown,
+    mut worker: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let mut backoff = RESTART_BACKOFF_START;
+    loop {
+        let started = Instant::now();
+        let e = match tokio::spawn(worker()).await {
+            Ok(()) => return,
+            Err(e) if e.is_panic() => e,
+            // The runtime is shutting down
+            Err(_) => return,
+        };
+        let panic = e.into_panic();
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("(no message)");
+        if started.elapsed() >= RESTART_BACKOFF_MAX {
+            backoff = RESTART_BACKOFF_START;
+        }
+        log::error!(
+            "background worker `{}` panicked, restarting in {:?}: {}",
+            name,
+            backoff,
+            message
+        );
+        if let Some(url) = &error_reports {
+            report_error(url, &root, name, message).await;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.clone() => return,
+        }
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    }
+}
+
+/// Tell the error tracker at `url` that the background worker `name` panicked or failed.  Failing to
+/// do so is only logged, the worker carries on (or gets restarted) either way.
+async fn report_error(url: &Url, root: &Url, name: &str, message: &str) {
+    let report = json!({
+        "instance": root.as_str(),
+        "worker": name,
+        "message": message,
+        "timestamp": Utc::now().to_rfc3339(),
+    });
+    let result = reqwest::Client::new()
+        .post(url.clone())
+        .json(&report)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    if let Err(e) = result {
+        log::error!("failed to report error of `{}`: {}", name, e);
+    }
 }
 
 #[must_use]
 pub enum UpdateResult {
     AllOk,
     NotEnoughOnline(usize),
+    /// Another update was still running, so this one was skipped
+    AlreadyRunning,
+}
+
+/// Managed state telling whether an update of the nodes is running, so that they do not overlap
+/// when an update takes longer than the interval between them
+#[derive(Default)]
+pub struct UpdateLock(AtomicBool);
+
+/// While this exists, the `UpdateLock` it came from is held
+struct UpdateGuard<'a>(&'a AtomicBool);
+
+impl UpdateLock {
+    fn try_lock(&self) -> Option<UpdateGuard<'_>> {
+        let was_running = self.0.swap(true, Ordering::Acquire);
+        (!was_running).then_some(UpdateGuard(&self.0))
+    }
+}
+
+impl Drop for UpdateGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Cache validators and contents of the last node list we fetched from each URL
+#[derive(Default)]
+pub struct NodesCache(Mutex<HashMap<Url, CachedNodes>>);
+
+struct CachedNodes {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    nodes: json::Nodes,
+}
+
+/// For each node list URL, how fetching it went recently
+#[derive(Default)]
+pub struct CircuitBreakers(Mutex<HashMap<Url, Breaker>>);
+
+#[derive(Default)]
+struct Breaker {
+    /// How often fetching failed in a row
+    failures: u32,
+    /// While the circuit is open, when to try again
+    retry_at: Option<Instant>,
+}
+
+/// The first pause after the circuit opened
+const BACKOFF_START: Duration = Duration::from_secs(60);
+
+impl CircuitBreakers {
+    /// Whether we should try fetching from `url` now
+    fn is_closed(&self, url: &Url) -> bool {
+        let breakers = self.0.lock().unwrap();
+        match breakers.get(url).and_then(|breaker| breaker.retry_at) {
+            Some(retry_at) => Instant::now() >= retry_at,
+            None => true,
+        }
+    }
+
+    /// Whether fetching `url` failed often enough in a row to open the circuit; it stays open
+    /// while retries keep failing
+    fn has_tripped(&self, url: &Url, config: &Config) -> bool {
+        let breakers = self.0.lock().unwrap();
+        breakers
+            .get(url)
+            .is_some_and(|breaker| breaker.failures >= config.ui.get_fetch_failure_threshold())
+    }
+
+    /// Record that fetching `url` failed, and return whether the circuit just opened
+    fn record_failure(&self, url: &Url, config: &Config) -> bool {
+        let mut breakers = self.0.lock().unwrap();
+        let breaker = breakers.entry(url.clone()).or_default();
+        breaker.failures += 1;
+        let threshold = config.ui.get_fetch_failure_threshold();
+        if breaker.failures < threshold {
+            return false;
+        }
+        let exponent = (breaker.failures - threshold).min(16);
+        let backoff = (BACKOFF_START * 2u32.pow(exponent)).min(config.ui.get_fetch_max_backoff());
+        breaker.retry_at = Some(Instant::now() + backoff);
+        breaker.failures == threshold
+    }
+
+    /// Record that fetching `url` worked, and return whether the circuit was open
+    fn record_success(&self, url: &Url, config: &Config) -> bool {
+        let mut breakers = self.0.lock().unwrap();
+        let breaker = breakers.remove(url).unwrap_or_default();
+        breaker.failures >= config.ui.get_fetch_failure_threshold()
+    }
+}
+
+/// The node lists whose data was too old when we last fetched them
+#[derive(Default)]
+pub struct StaleSources(Mutex<HashSet<Url>>);
+
+impl StaleSources {
+    /// Record whether the data from `url` is stale, and return whether that changed
+    fn update(&self, url: &Url, stale: bool) -> bool {
+        let mut stale_sources = self.0.lock().unwrap();
+        if stale {
+            stale_sources.insert(url.clone())
+        } else {
+            stale_sources.remove(url)
+        }
+    }
+}
+
+/// When we last notified about each node going online (`true`) or offline (`false`), to catch
+/// the same event being reported twice by overlapping runs
+#[derive(Default)]
+pub struct RecentEvents(Mutex<HashMap<(String, bool), Instant>>);
+
+impl RecentEvents {
+    /// Record that we are about to notify about `event`, and return whether we already did so
+    /// less than `window` ago
+    fn is_repeat(&self, event: &NodeEvent, window: Duration) -> bool {
+        let mut recent = self.0.lock().unwrap();
+        let now = Instant::now();
+        recent.retain(|_, at| now.duration_since(*at) < window);
+        let key = (event.node_id.clone(), event.to.is_online());
+        recent.insert(key, now).is_some()
+    }
 }
 
-/// Fetch the latest nodelist, update node state and send out emails
 impl<'r> Ctx<'r> {
-    pub async fn update_nodes(&self, db: &DbConn) -> Result<UpdateResult> {
-        let config = self.config();
-        let cur_nodes: json::Nodes = reqwest::get(config.urls.nodes.clone())
+    /// Fetch the latest node list from `url`, unless it did not change since we last fetched it
+    async fn fetch_nodes(&self, url: &Url) -> Result<json::Nodes> {
+        let cache = self.state::<NodesCache>().unwrap();
+        // A hanging upstream must not stall the update; a timeout is just another failure
+        let timeout = self.config().ui.get_fetch_timeout();
+        let builder = reqwest::Client::builder()
+            .connect_timeout(timeout)
+            .timeout(timeout);
+        let client = upstream::guard(url, self.config(), builder)
             .await?
+            .build()?;
+        let mut request = client.get(url.clone());
+        if let Some(cached) = cache.0.lock().unwrap().get(url) {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let response = request.send().await?.error_for_status()?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return match cache.0.lock().unwrap().get(url) {
+                Some(cached) => Ok(cached.nodes.clone()),
+                None => bail!("node list not modified, but we have no cached copy"),
+            };
+        }
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        // This transparently decompresses gzip-encoded responses
+        let nodes: json::Nodes = response
             .json()
-            .await?;
+            .await
+            .context("failed to read or decode node list")?;
+        if nodes.version != 2 {
+            bail!("unsupported hopglass node list version: {}", nodes.version);
+        }
+        cache.0.lock().unwrap().insert(
+            url.clone(),
+            CachedNodes {
+                etag,
+                last_modified,
+                nodes: nodes.clone(),
+            },
+        );
+        Ok(nodes)
+    }
 
-        if cur_nodes.version != 2 {
-            bail!(
-                "unsupported hopglass node list version: {}",
-                cur_nodes.version
-            );
+    /// The event a notification about the given node going `direction` would be about, with the
+    /// details from the last node list we fetched if the node was on it.  Without `direction`,
+    /// the node is assumed to just have reached its current state.
+    pub fn event_for(&self, node: models::NodeQuery, direction: Option<Direction>) -> NodeEvent {
+        let direction = direction.unwrap_or(if node.online {
+            Direction::Up
+        } else {
+            Direction::Down
+        });
+        let id = node.id.clone();
+        let (_, mut data) = model_to_node_data(node);
+        let cache = self.state::<NodesCache>().unwrap().0.lock().unwrap();
+        let listed = Source::all(self.config()).into_iter().find_map(|source| {
+            cache
+                .get(source.url)?
+                .nodes
+                .nodes
+                .iter()
+                .find_map(|cur_node| {
+                    let (cur_id, cur_data) = json_to_node_data(cur_node.clone())?;
+                    (source.node_id(cur_id) == id).then_some(cur_data)
+                })
+        });
+        if let Some(listed) = listed {
+            data.details = listed.details;
         }
+        data.into_event(id, direction, Utc::now())
+    }
 
-        // Build node HashMap: map node ID to name and online state
-        let mut cur_nodes_map: HashMap<String, NodeData> = HashMap::new();
-        for cur_node in cur_nodes.nodes.into_iter() {
-            if let Some((id, data)) = json_to_node_data(cur_node) {
-                cur_nodes_map.insert(id, data);
+    /// Fetch all node lists, and merge them.  Also returns the names of the lists that failed,
+    /// unless all of them did.
+    async fn fetch_all_nodes(&self) -> Result<(HashMap<String, NodeData>, Vec<Option<&str>>)> {
+        let config = self.config();
+        let breakers = self.state::<CircuitBreakers>().unwrap();
+        let stale_sources = self.state::<StaleSources>().unwrap();
+        let sources = Source::all(config);
+        if sources.is_empty() {
+            bail!("no node list configured");
+        }
+        let mut nodes = HashMap::new();
+        let mut failed = Vec::new();
+        let mut last_error = None;
+        for source in &sources {
+            if !breakers.is_closed(source.url) {
+                // Give the upstream some rest; this counts as failed, but quietly
+                failed.push(source.name);
+                continue;
+            }
+            let tripped = breakers.has_tripped(source.url, config);
+            match self.fetch_nodes(source.url).await {
+                Ok(cur_nodes) => {
+                    if breakers.record_success(source.url, config) {
+                        log::info!(
+                            "[{}] node list {} is reachable again",
+                            self.request_id(),
+                            source.url
+                        );
+                    }
+                    if let Some(stale_after) = config.ui.get_stale_after() {
+                        // If the list stopped being updated, that says nothing about the nodes,
+                        // so treat it like a list we could not fetch
+                        let updated = cur_nodes.timestamp;
+                        let stale = Utc::now() - updated > chrono::Duration::from_std(stale_after)?;
+                        if stale_sources.update(source.url, stale) {
+                            self.report_staleness(source.url, updated, stale).await;
+                        }
+                        if stale {
+                            failed.push(source.name);
+                            last_error = Some(anyhow!(
+                                "node list {} was last updated at {}",
+                                source.url,
+                                updated
+                            ));
+                            continue;
+                        }
+                    }
+                    for cur_node in cur_nodes.nodes.into_iter() {
+                        if let Some((id, data)) = json_to_node_data(cur_node) {
+                            nodes.insert(source.node_id(id), data);
+                        }
+                    }
+                }
+                Err(e) => {
+                    // While the circuit is open, the log already says that this list is down
+                    if tripped {
+                        log::debug!(
+                            "[{}] node list {} is still unreachable: {:#}",
+                            self.request_id(),
+                            source.url,
+                            e
+                        );
+                    } else {
+                        log::error!(
+                            "[{}] failed to fetch node list {}: {:#}",
+                            self.request_id(),
+                            source.url,
+                            e
+                        );
+                    }
+                    if breakers.record_failure(source.url, config) {
+                        log::error!(
+                            "[{}] circuit open: not fetching node list {} for a while",
+                            self.request_id(),
+                            source.url
+                        );
+                    }
+                    failed.push(source.name);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if failed.len() == sources.len() {
+            if let Some(e) = last_error {
+                return Err(e);
             }
         }
+        Ok((nodes, failed))
+    }
+
+    /// Tell the operator that the node list at `url` became stale, or that it is fresh again
+    async fn report_staleness(&self, url: &Url, updated: DateTime<Utc>, stale: bool) {
+        let config = self.config();
+        if stale {
+            log::error!(
+                "[{}] node list {} is stale: last updated at {}; not updating its nodes",
+                self.request_id(),
+                url,
+                updated
+            );
+        } else {
+            log::info!("[{}] node list {} is fresh again", self.request_id(), url);
+        }
+        let Some(operator_email) = &config.ui.operator_email else {
+            return;
+        };
+        let result = async {
+            let tz = config.ui.get_timezone();
+            let vals = json!({
+                "url": url.as_str(),
+                "updated": updated.with_timezone(&tz).format("%d.%m.%Y %H:%M").to_string(),
+                "stale": stale,
+            });
+            let message =
+                self.build_email("stale_alert", None, vals, operator_email.as_ref(), None)?;
+            self.mailer()?.send(message).await
+        }
+        .await;
+        if let Err(e) = result {
+            log::error!(
+                "[{}] failed to tell the operator about node list {}: {:#}",
+                self.request_id(),
+                url,
+                e
+            );
+        }
+    }
+}
+
+/// A node list
+#[derive(Clone, Copy)]
+struct Source<'a> {
+    /// The prefix of the IDs of the nodes from this list, if any
+    name: Option<&'a str>,
+    url: &'a Url,
+}
+
+impl<'a> Source<'a> {
+    fn all(config: &'a Config) -> Vec<Self> {
+        let main = config
+            .urls
+            .nodes
+            .as_ref()
+            .map(|url| Source { name: None, url });
+        let others = config.node_sources.iter().map(|source| Source {
+            name: Some(source.name.as_str()),
+            url: &source.url,
+        });
+        main.into_iter().chain(others).collect()
+    }
+
+    /// The ID under which we store the node with the given ID in this list
+    fn node_id(&self, id: String) -> String {
+        match self.name {
+            Some(name) => format!("{}:{}", name, id),
+            None => id,
+        }
+    }
+}
+
+/// The name of the list the node with the given (stored) ID comes from
+fn source_name(id: &str) -> Option<&str> {
+    id.split_once(':').map(|(name, _)| name)
+}
+
+/// For every watcher who gets combined emails, their language and the indices of the events to
+/// tell them about, with the pattern through which they monitor that node
+type CombinedEmails = BTreeMap<String, (Option<String>, Vec<(usize, Option<String>)>)>;
+
+/// Fetch the latest nodelist, update node state and send out emails
+impl<'r> Ctx<'r> {
+    pub async fn update_nodes(&self, db: &DbConn) -> Result<UpdateResult> {
+        let Some(_guard) = self.state::<UpdateLock>().unwrap().try_lock() else {
+            log::warn!(
+                "[{}] skipping update, the previous one is still running",
+                self.request_id()
+            );
+            self.metrics().count_skipped_update();
+            return Ok(UpdateResult::AlreadyRunning);
+        };
+        let config = self.config();
+        // Build node HashMap: map node ID to name and online state
+        let (cur_nodes_map, failed_sources) = self.fetch_all_nodes().await?;
+        let failed_sources: Vec<Option<String>> = failed_sources
+            .into_iter()
+            .map(|name| name.map(str::to_owned))
+            .collect();
 
         // Stop here if nearly all nodes are offline
-        let online_nodes = cur_nodes_map.values().filter(|data| data.online).count();
+        let online_nodes = cur_nodes_map
+            .values()
+            .filter(|data| data.state.is_online())
+            .count();
         if online_nodes < config.ui.min_online_nodes.unwrap_or(0) {
             return Ok(UpdateResult::NotEnoughOnline(online_nodes));
         }
 
-        // Compute which nodes changed their state, also update node names in DB
-        let changed: Vec<(String, NodeData)> = db
+        // Compute which nodes changed their state, and bring the DB up to date
+        let flap_suppress = chrono::Duration::from_std(config.ui.get_flap_suppress())?;
+        let now = Utc::now();
+        let mut changed: Vec<NodeEvent> = db
             .run(move |db| {
                 db.transaction::<_, anyhow::Error, _>(|db| {
                     {
-                        let mut changed = Vec::new();
-
-                        // Go over every node in the database
                         let db_nodes = nodes::table.load::<models::NodeQuery>(db)?;
-                        for db_node in db_nodes.into_iter() {
-                            let (id, db_data) = model_to_node_data(db_node);
-                            if let Some(cur_data) = cur_nodes_map.remove(&id) {
-                                // We already know this node.
-                                // Did it change?
-                                if cur_data != db_data {
-                                    // Update in database
-                                    diesel::update(nodes::table.find(id.as_str()))
-                                        .set((
-                                            nodes::name.eq(cur_data.name.as_str()),
-                                            nodes::online.eq(cur_data.online),
-                                        ))
-                                        .execute(db)?;
-                                }
-                                // Did its online status change?
-                                if cur_data.online != db_data.online {
-                                    changed.push((id, cur_data));
-                                }
-                            } else {
-                                // The node is in the DB but does not exist any more.
-                                diesel::delete(nodes::table.find(id.as_str())).execute(db)?;
-                                if db_data.online {
-                                    // The node was online, so it being gone is a change to offline
-                                    changed.push((
-                                        id,
-                                        NodeData {
-                                            online: false,
-                                            ..db_data
-                                        },
-                                    ));
-                                }
-                            }
+                        // We know nothing new about the nodes from lists we could not fetch
+                        let skip = |id: &str| {
+                            let source = source_name(id);
+                            failed_sources.iter().any(|name| name.as_deref() == source)
+                        };
+                        let (changed, node_changes) =
+                            diff_nodes(db_nodes, cur_nodes_map, skip, flap_suppress, now);
+                        for node_change in node_changes {
+                            apply_node_change(db, node_change)?;
                         }
 
-                        // Go over nodes remaining in the hash map -- they are not in the DB
-                        for (id, cur_data) in cur_nodes_map.into_iter() {
-                            // Insert into DB
-                            diesel::insert_into(nodes::table)
-                                .values(&models::Node {
-                                    id: id.as_str(),
-                                    name: cur_data.name.as_str(),
-                                    online: cur_data.online,
-                                })
-                                .execute(db)?;
-                            if cur_data.online {
-                                // The node is online, so it appearing is a change from the implicit offline
-                                // it was in when it did not exist.
-                                changed.push((id, cur_data));
-                            }
-                        }
+                        // Remember the changes for the node history
+                        let history: Vec<_> = changed
+                            .iter()
+                            .map(|event| models::HistoryEntry {
+                                node_id: event.node_id.as_str(),
+                                online: event.to.is_online(),
+                                at: event.at,
+                            })
+                            .collect();
+                        diesel::insert_into(node_history::table)
+                            .values(&history)
+                            .execute(db)?;
+
+                        let ids: Vec<&str> = changed.iter().map(|e| e.node_id.as_str()).collect();
+                        let notified: HashMap<String, bool> = notified_states::table
+                            .filter(notified_states::node_id.eq_any(&ids))
+                            .select((notified_states::node_id, notified_states::online))
+                            .load::<(String, bool)>(db)?
+                            .into_iter()
+                            .collect();
+                        let changed = not_notified(&notified, changed);
+                        let states: Vec<_> = changed
+                            .iter()
+                            .map(|event| models::NotifiedState {
+                                node_id: event.node_id.as_str(),
+                                online: event.to.is_online(),
+                                notified_at: event.at,
+                            })
+                            .collect();
+                        diesel::insert_into(notified_states::table)
+                            .values(&states)
+                            .on_conflict(notified_states::node_id)
+                            .do_update()
+                            .set((
+                                notified_states::online.eq(excluded(notified_states::online)),
+                                notified_states::notified_at
+                                    .eq(excluded(notified_states::notified_at)),
+                            ))
+                            .execute(db)?;
 
                         Ok(changed)
                     }
                 })
             })
             .await?;
+        // Runs that overlap can both see the old state; only the first one gets to notify
+        let recent_events = self.state::<RecentEvents>().unwrap();
+        let dedup_window = config.ui.get_dedup_window();
+        changed.retain(|event| {
+            let repeat = recent_events.is_repeat(event, dedup_window);
+            if repeat {
+                log::warn!(
+                    "[{}] not notifying again about {} going {}",
+                    self.request_id(),
+                    event.node_id,
+                    if event.to.is_online() {
+                        "online"
+                    } else {
+                        "offline"
+                    }
+                );
+            }
+            !repeat
+        });
+
+        // Forget about actions that were not confirmed in time, about old history, and about
+        // idempotency keys that clients may no longer repeat
+        let expired = now - chrono::Duration::from_std(config.ui.get_pending_ttl())?;
+        let history_start = now - chrono::Duration::from_std(config.ui.get_history_retention())?;
+        let keys_expired = now - chrono::Duration::from_std(routes::IDEMPOTENCY_KEY_TTL)?;
+        let purged = db
+            .run(move |db| {
+                let purged = diesel::delete(
+                    pending_actions::table.filter(pending_actions::sent_at.lt(expired)),
+                )
+                .execute(db)?;
+                diesel::delete(
+                    idempotency_keys::table.filter(idempotency_keys::created_at.lt(keys_expired)),
+                )
+                .execute(db)?;
+                diesel::delete(node_history::table.filter(node_history::at.lt(history_start)))
+                    .execute(db)?;
+                // A node we know nothing about counts as offline anyway
+                diesel::delete(
+                    notified_states::table
+                        .filter(notified_states::online.eq(false))
+                        .filter(not(
+                            notified_states::node_id.eq_any(nodes::table.select(nodes::id))
+                        )),
+                )
+                .execute(db)?;
+                Ok::<_, diesel::result::Error>(purged)
+            })
+            .await?;
+        log::info!(
+            "[{}] purged {} unconfirmed actions",
+            self.request_id(),
+            purged
+        );
 
         // Send out notifications (not in the transaction as we don't really care here -- also
         // we have an external side-effect, the email, which we cannot roll back anyway)
-        for (id, cur_data) in changed.into_iter() {
-            // See who monitors this node
-            let watchers = db
+        let email_notifier = EmailNotifier::new(self, db)?;
+        let matrix_notifier = config
+            .matrix
+            .as_ref()
+            .map(|matrix| MatrixNotifier::new(self, matrix));
+        let webhook_notifier = config
+            .webhook
+            .as_ref()
+            .map(|webhook| WebhookNotifier::new(self, webhook));
+        // Emails left over from earlier runs go first
+        self.mailer()?.retry_outbox(db).await?;
+        let mut failed = self.send_queued(db, &email_notifier, now).await?;
+        failed += self.send_digests(db, now).await?;
+        failed += self.send_weekly_summaries(db, now).await?;
+        // All events, and for every watcher who gets combined emails, which of them to tell them
+        // about (and through which pattern, if any)
+        let mut events = Vec::with_capacity(changed.len());
+        let mut combined: CombinedEmails = BTreeMap::new();
+        let pattern_monitors = db
+            .run(|db| {
+                monitors::table
+                    .filter(monitors::id.like("%*"))
+                    .order_by(monitors::id)
+                    .load::<models::MonitorQuery>(db)
+            })
+            .await?;
+        for event in changed.into_iter() {
+            let (id, direction) = (event.node_id.clone(), event.direction());
+            self.metrics().count_transition(direction);
+            // See who monitors this node, directly or through a pattern.  Everyone hears about it
+            // only once; if they monitor the node directly, with the settings of that monitor.
+            let mut watchers = db
                 .run({
                     let id = id.clone();
                     move |db| {
@@ -221,25 +1045,790 @@ impl<'r> Ctx<'r> {
                     }
                 })
                 .await?;
-            // Send them email
-            let node = cur_data.into_model(id);
-            for watcher in watchers.iter() {
-                // Generate email text
-                let email = EmailAddress::new(watcher.email.clone()).unwrap();
-                let list_url = config.urls.absolute(uri!(routes::list(email = &email)));
-                // Build and send email
-                self.email(
-                    "notification",
+            let mut emails: HashSet<String> = watchers.iter().map(|w| w.email.clone()).collect();
+            for monitor in &pattern_monitors {
+                if node_prefix(&monitor.id).is_some_and(|prefix| id.starts_with(prefix))
+                    && emails.insert(monitor.email.clone())
+                {
+                    watchers.push(monitor.clone());
+                }
+            }
+            let monitored = !watchers.is_empty();
+            // Sort them by backend.  If Matrix is not configured (any more), fall back to email.
+            // Emails to watchers that are in their quiet hours are queued instead, and events for
+            // watchers that want a digest are recorded for the next digest.  All other emails
+            // are sent after we went through all events, so that they can be combined.
+            let mut email_watchers = Vec::new();
+            let mut matrix_watchers = Vec::new();
+            let mut quiet_watchers = Vec::new();
+            let mut digest_watchers = Vec::new();
+            for watcher in watchers.into_iter() {
+                let notify_mode = NotifyMode::from_db(&watcher.notify_mode)?;
+                let pattern = (watcher.id != id).then(|| watcher.id.clone());
+                match (Backend::from_db(&watcher.backend)?, &matrix_notifier) {
+                    (Backend::Matrix, Some(_)) => matrix_watchers.push(Watcher {
+                        email: watcher.email,
+                        lang: watcher.lang,
+                        pattern,
+                    }),
+                    _ if notify_mode == NotifyMode::Digest => {
+                        digest_watchers.push((watcher.email, watcher.id))
+                    }
+                    _ => {
+                        let quiet_hours = QuietHours::from_db(
+                            watcher.quiet_start,
+                            watcher.quiet_end,
+                            watcher.timezone.as_deref(),
+                        );
+                        match quiet_hours.and_then(|quiet_hours| quiet_hours.end_after(now)) {
+                            Some(end) => quiet_watchers.push((watcher.email, watcher.id, end)),
+                            None if notify_mode == NotifyMode::Separate => {
+                                email_watchers.push(Watcher {
+                                    email: watcher.email,
+                                    lang: watcher.lang,
+                                    pattern,
+                                })
+                            }
+                            None => combined
+                                .entry(watcher.email)
+                                .or_insert_with(|| (watcher.lang, Vec::new()))
+                                .1
+                                .push((events.len(), pattern)),
+                        }
+                    }
+                }
+            }
+            if !quiet_watchers.is_empty() {
+                queue(db, id.clone(), direction, quiet_watchers).await?;
+            }
+            // Notify them
+            if !digest_watchers.is_empty() {
+                let deliver_at = next_digest(config, now);
+                record_for_digest(db, &event, deliver_at, digest_watchers).await?;
+            }
+            let mut notifiers: Vec<(&dyn Notifier, &[Watcher])> = Vec::new();
+            if !email_watchers.is_empty() {
+                notifiers.push((&email_notifier, &email_watchers));
+            }
+            if let (Some(matrix_notifier), false) = (&matrix_notifier, matrix_watchers.is_empty()) {
+                notifiers.push((matrix_notifier, &matrix_watchers));
+            }
+            // The webhook hears about every monitored node, independent of the backends
+            if let (Some(webhook_notifier), true) = (&webhook_notifier, monitored) {
+                notifiers.push((webhook_notifier, &[]));
+            }
+            for (notifier, watchers) in notifiers {
+                if let Err(e) = notifier.notify(&event, watchers).await {
+                    log::error!(
+                        "[{}] failed to notify about {}: {:#}",
+                        self.request_id(),
+                        event.node_id,
+                        e
+                    );
+                    failed += 1;
+                }
+            }
+            events.push(event);
+        }
+        let batches: Vec<_> = combined
+            .into_iter()
+            .map(|(email, (lang, mut indices))| {
+                // Only an email about a single event links to stopping the monitor
+                let pattern = match &mut indices[..] {
+                    [(_, pattern)] => pattern.take(),
+                    _ => None,
+                };
+                let watcher = Watcher {
+                    email,
+                    lang,
+                    pattern,
+                };
+                (
+                    watcher,
+                    indices.into_iter().map(|(i, _)| &events[i]).collect(),
+                )
+            })
+            .collect();
+        if !batches.is_empty() {
+            if let Err(e) = email_notifier.notify_combined(&batches).await {
+                log::error!(
+                    "[{}] failed to send combined notifications: {:#}",
+                    self.request_id(),
+                    e
+                );
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            bail!("{} notifications failed", failed);
+        }
+
+        Ok(UpdateResult::AllOk)
+    }
+}
+
+/// Queue the notification about a node changing its state for the given watchers (with the ID of
+/// their monitor), who are in their quiet hours until the given time.  If a notification about
+/// that node is already queued for a watcher, the node went back to the state the watcher last
+/// heard about, so instead of queueing another notification we drop the queued one.
+async fn queue(
+    db: &DbConn,
+    id: String,
+    direction: Direction,
+    watchers: Vec<(String, String, DateTime<Utc>)>,
+) -> Result<()> {
+    db.run(move |db| {
+        db.transaction::<_, anyhow::Error, _>(|db| {
+            for (email, monitor_id, deliver_at) in watchers {
+                let queued = queued_notifications::table.find((id.as_str(), email.as_str()));
+                if diesel::delete(queued).execute(db)? == 0 {
+                    diesel::insert_into(queued_notifications::table)
+                        .values(&models::QueuedNotification {
+                            id: id.as_str(),
+                            email: email.as_str(),
+                            online: direction.new_state().is_online(),
+                            deliver_at,
+                            monitor_id: monitor_id.as_str(),
+                        })
+                        .execute(db)?;
+                }
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+impl<'r> Ctx<'r> {
+    /// Send the queued notifications whose watchers' quiet hours are over, and return how many of
+    /// them failed
+    async fn send_queued(
+        &self,
+        db: &DbConn,
+        email_notifier: &EmailNotifier<'_>,
+        now: DateTime<Utc>,
+    ) -> Result<usize> {
+        let due = db
+            .run(move |db| {
+                db.transaction::<_, anyhow::Error, _>(|db| {
+                    let due = queued_notifications::table
+                        .filter(queued_notifications::deliver_at.le(now));
+                    let queued = due
+                        .left_join(nodes::table.on(queued_notifications::id.eq(nodes::id)))
+                        .left_join(
+                            monitors::table.on(queued_notifications::monitor_id
+                                .eq(monitors::id)
+                                .and(queued_notifications::email.eq(monitors::email))),
+                        )
+                        .select((
+                            queued_notifications::id,
+                            queued_notifications::email,
+                            queued_notifications::online,
+                            nodes::name.nullable(),
+                            monitors::lang.nullable(),
+                            queued_notifications::monitor_id,
+                        ))
+                        .load::<(String, String, bool, Option<String>, Option<String>, String)>(
+                            db,
+                        )?;
+                    diesel::delete(due).execute(db)?;
+                    Ok(queued)
+                })
+            })
+            .await?;
+
+        let mut failed = 0;
+        for (id, email, online, name, lang, monitor_id) in due {
+            let pattern = (monitor_id != id).then_some(monitor_id);
+            let direction = if online {
+                Direction::Up
+            } else {
+                Direction::Down
+            };
+            let event = NodeEvent {
+                // The node might be gone by now
+                name: name.unwrap_or_else(|| id.clone()),
+                node_id: id,
+                from: direction.old_state(),
+                to: direction.new_state(),
+                // We do not remember when exactly this happened
+                at: now,
+                details: NodeDetails::default(),
+            };
+            if let Err(e) = email_notifier
+                .notify(
+                    &event,
+                    &[Watcher {
+                        email,
+                        lang,
+                        pattern,
+                    }],
+                )
+                .await
+            {
+                log::error!(
+                    "[{}] failed to send queued notification about {}: {:#}",
+                    self.request_id(),
+                    event.node_id,
+                    e
+                );
+                failed += 1;
+            }
+        }
+        Ok(failed)
+    }
+}
+
+/// The first time the daily digest goes out after `now`
+fn next_digest(config: &Config, now: DateTime<Utc>) -> DateTime<Utc> {
+    let tz = config.ui.get_timezone();
+    let hour = NaiveTime::from_hms_opt(config.ui.get_digest_hour(), 0, 0).unwrap();
+    let local = now.with_timezone(&tz);
+    let mut date = local.date_naive();
+    if local.time() >= hour {
+        date = date + Days::new(1);
+    }
+    match tz.from_local_datetime(&date.and_time(hour)).earliest() {
+        Some(next) => next.with_timezone(&Utc),
+        // The hour does not exist on that day due to a DST change
+        None => now + chrono::Duration::days(1),
+    }
+}
+
+/// How far back the weekly summary looks
+const SUMMARY_DAYS: i64 = 7;
+
+/// The first time the weekly summary goes out after `now`: on Monday, at the hour the daily
+/// digest goes out
+fn next_weekly_summary(config: &Config, now: DateTime<Utc>) -> DateTime<Utc> {
+    let tz = config.ui.get_timezone();
+    let hour = NaiveTime::from_hms_opt(config.ui.get_digest_hour(), 0, 0).unwrap();
+    let local = now.with_timezone(&tz);
+    let mut date = local.date_naive();
+    let days_until_monday = (7 - date.weekday().num_days_from_monday()) % 7;
+    date = date + Days::new(days_until_monday.into());
+    if days_until_monday == 0 && local.time() >= hour {
+        date = date + Days::new(7);
+    }
+    match tz.from_local_datetime(&date.and_time(hour)).earliest() {
+        Some(next) => next.with_timezone(&Utc),
+        // The hour does not exist on that day due to a DST change
+        None => now + chrono::Duration::days(SUMMARY_DAYS),
+    }
+}
+
+/// The share of the time from `start` to `end` that a node was online, given the state changes
+/// after `start`, oldest first, and whether it was online at `start`.  If the latter is not known,
+/// only the time since the first change counts.
+fn uptime_share(
+    mut start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    online: Option<bool>,
+    mut changes: &[(DateTime<Utc>, bool)],
+) -> f64 {
+    let mut online = match (online, changes) {
+        (Some(online), _) => online,
+        (None, [(at, online), rest @ ..]) => {
+            start = *at;
+            changes = rest;
+            *online
+        }
+        (None, []) => return 0.0,
+    };
+    let mut online_millis = 0;
+    let mut since = start;
+    for &(at, now_online) in changes {
+        if online {
+            online_millis += (at - since).num_milliseconds();
+        }
+        online = now_online;
+        since = at;
+    }
+    if online {
+        online_millis += (end - since).num_milliseconds();
+    }
+    online_millis as f64 / (end - start).num_milliseconds().max(1) as f64
+}
+
+/// Record an event for the next digest of the given watchers (with the ID of their monitor)
+async fn record_for_digest(
+    db: &DbConn,
+    event: &NodeEvent,
+    deliver_at: DateTime<Utc>,
+    watchers: Vec<(String, String)>,
+) -> Result<()> {
+    let node_id = event.node_id.clone();
+    let name = event.name.clone();
+    let online = event.to.is_online();
+    let at = event.at;
+    db.run(move |db| {
+        let entries: Vec<_> = watchers
+            .iter()
+            .map(|(email, monitor_id)| models::DigestEntry {
+                email: email.as_str(),
+                node_id: node_id.as_str(),
+                name: name.as_str(),
+                online,
+                at,
+                deliver_at,
+                monitor_id: monitor_id.as_str(),
+            })
+            .collect();
+        diesel::insert_into(digest_entries::table)
+            .values(&entries)
+            .execute(db)
+    })
+    .await?;
+    Ok(())
+}
+
+impl<'r> Ctx<'r> {
+    /// Send the digests that are due, and return how many of them failed.  Watchers for whom
+    /// nothing happened do not get a digest at all.
+    async fn send_digests(&self, db: &DbConn, now: DateTime<Utc>) -> Result<usize> {
+        let config = self.config();
+        let due = db
+            .run(move |db| {
+                db.transaction::<_, anyhow::Error, _>(|db| {
+                    let due = digest_entries::table.filter(digest_entries::deliver_at.le(now));
+                    let entries = due
+                        .order_by((digest_entries::email, digest_entries::at))
+                        .load::<models::DigestEntryQuery>(db)?;
+                    diesel::delete(due).execute(db)?;
+                    // The digest covers several subscriptions; use the language of any of them
+                    let emails: Vec<&str> = entries.iter().map(|e| e.email.as_str()).collect();
+                    let langs: HashMap<String, String> = monitors::table
+                        .filter(monitors::email.eq_any(emails))
+                        .filter(monitors::lang.is_not_null())
+                        .select((monitors::email, monitors::lang.assume_not_null()))
+                        .load::<(String, String)>(db)?
+                        .into_iter()
+                        .collect();
+                    Ok((entries, langs))
+                })
+            })
+            .await?;
+        let (due, langs) = due;
+        if due.is_empty() {
+            return Ok(0);
+        }
+
+        // Group the entries by watcher; they are already sorted that way
+        let mut digests: Vec<(String, Vec<serde_json::Value>)> = Vec::new();
+        let tz = config.ui.get_timezone();
+        for entry in due {
+            let vals = json!({
+                "node": {
+                    "id": entry.node_id,
+                    "name": entry.name,
+                    "online": entry.online,
+                },
+                "at": entry.at.with_timezone(&tz).format("%d.%m.%Y %H:%M").to_string(),
+            });
+            match digests.last_mut() {
+                Some((email, entries)) if *email == entry.email => entries.push(vals),
+                _ => digests.push((entry.email, vec![vals])),
+            }
+        }
+
+        let mut messages = Vec::with_capacity(digests.len());
+        for (email, entries) in digests {
+            let address = email
+                .parse::<EmailAddress>()
+                .map_err(|e| anyhow!("{}", e))?;
+            let list_url = config.urls.absolute(uri!(routes::list(email = &address)));
+            let unsubscribe_all_url = routes::unsubscribe_all_url(config, address);
+            messages.push(self.build_email(
+                "digest",
+                langs.get(&email).map(String::as_str),
+                json!({
+                    "entries": entries,
+                    "list_url": list_url,
+                    "unsubscribe_all_url": unsubscribe_all_url,
+                }),
+                &email,
+                None,
+            )?);
+        }
+        let mut failed = 0;
+        for result in self.mailer()?.send_all_durably(db, messages).await? {
+            if let Err(e) = result {
+                log::error!("[{}] failed to send digest: {:#}", self.request_id(), e);
+                failed += 1;
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Send the weekly summaries that are due, and return how many of them failed.  The first
+    /// summary for a new subscriber is scheduled for the next regular date, and addresses that no
+    /// longer have any nodes in their summary are forgotten.
+    async fn send_weekly_summaries(&self, db: &DbConn, now: DateTime<Utc>) -> Result<usize> {
+        let config = self.config();
+        let next = next_weekly_summary(config, now);
+        let start = now - chrono::Duration::days(SUMMARY_DAYS);
+        let due = db
+            .run(move |db| {
+                db.transaction::<_, anyhow::Error, _>(|db| {
+                    let monitors = monitors::table
+                        .filter(monitors::weekly_summary.eq(true))
+                        .order_by((monitors::email, monitors::id))
+                        .load::<models::MonitorQuery>(db)?;
+                    let mut emails: Vec<&str> = monitors.iter().map(|m| m.email.as_str()).collect();
+                    emails.dedup();
+                    diesel::delete(
+                        weekly_summaries::table
+                            .filter(not(weekly_summaries::email.eq_any(&emails))),
+                    )
+                    .execute(db)?;
+                    let scheduled: Vec<_> = emails
+                        .iter()
+                        .map(|email| models::WeeklySummary {
+                            email,
+                            deliver_at: next,
+                        })
+                        .collect();
+                    diesel::insert_into(weekly_summaries::table)
+                        .values(&scheduled)
+                        .on_conflict_do_nothing()
+                        .execute(db)?;
+                    let due: HashSet<String> = diesel::update(
+                        weekly_summaries::table.filter(weekly_summaries::deliver_at.le(now)),
+                    )
+                    .set(weekly_summaries::deliver_at.eq(next))
+                    .returning(weekly_summaries::email)
+                    .get_results::<String>(db)?
+                    .into_iter()
+                    .collect();
+                    if due.is_empty() {
+                        return Ok(None);
+                    }
+                    let monitors: Vec<_> = monitors
+                        .into_iter()
+                        .filter(|m| due.contains(&m.email))
+                        .collect();
+                    let nodes = nodes::table
+                        .order_by(nodes::name)
+                        .load::<models::NodeQuery>(db)?;
+                    // The state of each node at `start`, as far as we know it
+                    let initial: HashMap<String, bool> = node_history::table
+                        .filter(node_history::at.le(start))
+                        .order_by((node_history::node_id, node_history::at.desc()))
+                        .distinct_on(node_history::node_id)
+                        .select((node_history::node_id, node_history::online))
+                        .load::<(String, bool)>(db)?
+                        .into_iter()
+                        .collect();
+                    let mut changes: HashMap<String, Vec<(DateTime<Utc>, bool)>> = HashMap::new();
+                    for (node_id, at, online) in node_history::table
+                        .filter(node_history::at.gt(start))
+                        .order_by(node_history::at)
+                        .select((
+                            node_history::node_id,
+                            node_history::at,
+                            node_history::online,
+                        ))
+                        .load::<(String, DateTime<Utc>, bool)>(db)?
+                    {
+                        changes.entry(node_id).or_default().push((at, online));
+                    }
+                    Ok(Some((monitors, nodes, initial, changes)))
+                })
+            })
+            .await?;
+        let Some((monitors, nodes, initial, changes)) = due else {
+            return Ok(0);
+        };
+
+        // Group the monitors by watcher; they are already sorted that way
+        let mut summaries: Vec<(String, Vec<models::MonitorQuery>)> = Vec::new();
+        for monitor in monitors {
+            match summaries.last_mut() {
+                Some((email, monitors)) if *email == monitor.email => monitors.push(monitor),
+                _ => summaries.push((monitor.email.clone(), vec![monitor])),
+            }
+        }
+
+        let tz = config.ui.get_timezone();
+        let format = |at: DateTime<Utc>| at.with_timezone(&tz).format("%d.%m.%Y %H:%M").to_string();
+        let mut messages = Vec::new();
+        let mut failed = 0;
+        for (email, monitors) in summaries {
+            // The nodes covered by the monitors, each once
+            let covered: Vec<&models::NodeQuery> = nodes
+                .iter()
+                .filter(|node| {
+                    monitors
+                        .iter()
+                        .any(|monitor| match node_prefix(&monitor.id) {
+                            Some(prefix) => node.id.starts_with(prefix),
+                            None => monitor.id == node.id,
+                        })
+                })
+                .collect();
+            if covered.is_empty() {
+                continue;
+            }
+            let entries: Vec<_> = covered
+                .into_iter()
+                .map(|node| {
+                    let changes = changes.get(&node.id).map(Vec::as_slice).unwrap_or_default();
+                    // Without any record, the node has been in its current state all along;
+                    // with only newer records, we know nothing before the first of them
+                    let initially_online = match initial.get(&node.id) {
+                        Some(&online) => Some(online),
+                        None if changes.is_empty() => Some(node.online),
+                        None => None,
+                    };
+                    let uptime = uptime_share(start, now, initially_online, changes);
                     json!({
                         "node": node,
-                        "list_url": list_url.as_str(),
+                        "uptime": format!("{:.1}", uptime * 100.0),
+                        "outages": changes.iter().filter(|(_, online)| !online).count(),
+                    })
+                })
+                .collect();
+            // The summary covers several subscriptions; use the language of any of them
+            let lang = monitors.iter().find_map(|monitor| monitor.lang.clone());
+            // The summary is not due again until next week, so one address we cannot write to
+            // must not keep the others from getting theirs
+            let message = || -> Result<lettre::Message> {
+                let address = email
+                    .parse::<EmailAddress>()
+                    .map_err(|e| anyhow!("{}", e))?;
+                let list_url = config.urls.absolute(uri!(routes::list(email = &address)));
+                let unsubscribe_all_url = routes::unsubscribe_all_url(config, address);
+                self.build_email(
+                    "weekly_summary",
+                    lang.as_deref(),
+                    json!({
+                        "entries": entries,
+                        "from": format(start),
+                        "to": format(now),
+                        "list_url": list_url,
+                        "unsubscribe_all_url": unsubscribe_all_url,
                     }),
-                    watcher.email.as_str(),
+                    &email,
+                    None,
                 )
-                .await?;
+            };
+            match message() {
+                Ok(message) => messages.push(message),
+                Err(e) => {
+                    log::error!(
+                        "[{}] failed to build weekly summary for {}: {:#}",
+                        self.request_id(),
+                        email,
+                        e
+                    );
+                    failed += 1;
+                }
+            }
+        }
+        for result in self.mailer()?.send_all_durably(db, messages).await? {
+            if let Err(e) = result {
+                log::error!(
+                    "[{}] failed to send weekly summary: {:#}",
+                    self.request_id(),
+                    e
+                );
+                failed += 1;
             }
         }
+        Ok(failed)
+    }
+}
 
-        Ok(UpdateResult::AllOk)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: &str, direction: Direction) -> NodeEvent {
+        let data = NodeData {
+            name: id.to_owned(),
+            state: NodeState::from_online(direction == Direction::Up),
+            details: NodeDetails::default(),
+        };
+        data.into_event(id.to_owned(), direction, Utc::now())
+    }
+
+    #[test]
+    fn repeated_changes_are_notified_once() {
+        let mut last_notified = HashMap::new();
+        let mut sent = 0;
+        for direction in [
+            Direction::Down,
+            Direction::Down,
+            Direction::Up,
+            Direction::Up,
+        ] {
+            for event in not_notified(&last_notified, vec![event("node", direction)]) {
+                last_notified.insert(event.node_id, event.to.is_online());
+                sent += 1;
+            }
+        }
+        assert_eq!(sent, 2);
+    }
+
+    #[test]
+    fn other_nodes_are_not_affected() {
+        let last_notified = HashMap::from([("a".to_owned(), false)]);
+        let events = vec![event("a", Direction::Down), event("b", Direction::Down)];
+        let sent: Vec<_> = not_notified(&last_notified, events)
+            .into_iter()
+            .map(|event| event.node_id)
+            .collect();
+        assert_eq!(sent, ["b"]);
+    }
+
+    #[test]
+    fn repeats_inside_the_dedup_window() {
+        let window = Duration::from_secs(60);
+        let recent = RecentEvents::default();
+        assert!(!recent.is_repeat(&event("a", Direction::Down), window));
+        assert!(recent.is_repeat(&event("a", Direction::Down), window));
+        // The opposite transition, and the same one for another node, are no repeats
+        assert!(!recent.is_repeat(&event("a", Direction::Up), window));
+        assert!(!recent.is_repeat(&event("b", Direction::Down), window));
+    }
+
+    #[test]
+    fn repeats_outside_the_dedup_window() {
+        let window = Duration::from_millis(20);
+        let recent = RecentEvents::default();
+        assert!(!recent.is_repeat(&event("a", Direction::Down), window));
+        std::thread::sleep(window * 2);
+        assert!(!recent.is_repeat(&event("a", Direction::Down), window));
+    }
+
+    /// One case for `diff_nodes`: a node as it is in the database and in the node list (`None`
+    /// if missing there), and what should come out of that
+    struct Case {
+        name: &'static str,
+        /// Whether the node is online, and since how many seconds a change is pending
+        db: Option<(bool, Option<i64>)>,
+        /// Whether the node is online, and its name
+        listed: Option<(bool, &'static str)>,
+        flap_suppress_secs: i64,
+        event: Option<(NodeState, NodeState)>,
+        change: Option<NodeChange>,
+    }
+
+    #[test]
+    fn diff_nodes_cases() {
+        use NodeState::{Offline, Online};
+
+        let now = Utc::now();
+        let ago = |secs| Some(now - chrono::Duration::seconds(secs));
+        let update = |name: &str, online, pending_since| NodeChange::Update {
+            id: "node".to_owned(),
+            name: name.to_owned(),
+            online,
+            pending_since,
+        };
+        let insert = |online, pending_since| NodeChange::Insert {
+            id: "node".to_owned(),
+            name: "name".to_owned(),
+            online,
+            pending_since,
+        };
+        #[rustfmt::skip]
+        let cases = [
+            Case { name: "appear", db: None, listed: Some((true, "name")), flap_suppress_secs: 0,
+                event: Some((Offline, Online)), change: Some(insert(true, None)) },
+            Case { name: "disappear online", db: Some((true, None)), listed: None,
+                flap_suppress_secs: 0,
+                event: Some((Online, Offline)), change: Some(NodeChange::Delete("node".to_owned())) },
+            Case { name: "disappear offline", db: Some((false, None)), listed: None,
+                flap_suppress_secs: 0,
+                event: None, change: Some(NodeChange::Delete("node".to_owned())) },
+            Case { name: "stay online", db: Some((true, None)), listed: Some((true, "name")),
+                flap_suppress_secs: 0, event: None, change: None },
+            Case { name: "stay offline", db: Some((false, None)), listed: Some((false, "name")),
+                flap_suppress_secs: 0, event: None, change: None },
+            Case { name: "rename", db: Some((false, None)), listed: Some((false, "new name")),
+                flap_suppress_secs: 0, event: None, change: Some(update("new name", None, None)) },
+            Case { name: "flip up", db: Some((false, None)), listed: Some((true, "name")),
+                flap_suppress_secs: 0,
+                event: Some((Offline, Online)), change: Some(update("name", Some(true), None)) },
+            Case { name: "flip down", db: Some((true, None)), listed: Some((false, "name")),
+                flap_suppress_secs: 0,
+                event: Some((Online, Offline)), change: Some(update("name", Some(false), None)) },
+            Case { name: "new online node", db: None, listed: Some((true, "name")),
+                flap_suppress_secs: 60, event: None, change: Some(insert(false, Some(now))) },
+            Case { name: "new offline node", db: None, listed: Some((false, "name")),
+                flap_suppress_secs: 60, event: None, change: Some(insert(false, None)) },
+            Case { name: "flap_suppress pending", db: Some((true, None)),
+                listed: Some((false, "name")), flap_suppress_secs: 60,
+                event: None, change: Some(update("name", None, Some(now))) },
+            Case { name: "flap_suppress still pending", db: Some((true, Some(10))),
+                listed: Some((false, "name")), flap_suppress_secs: 60,
+                event: None, change: Some(update("name", None, ago(10))) },
+            Case { name: "flap_suppress over", db: Some((true, Some(60))),
+                listed: Some((false, "name")), flap_suppress_secs: 60,
+                event: Some((Online, Offline)), change: Some(update("name", Some(false), None)) },
+            Case { name: "flap_suppress flapped back", db: Some((true, Some(10))),
+                listed: Some((true, "name")), flap_suppress_secs: 60,
+                event: None, change: Some(update("name", None, None)) },
+            Case { name: "flap_suppress disappear pending", db: Some((true, Some(10))),
+                listed: None, flap_suppress_secs: 60,
+                event: None, change: Some(update("name", None, ago(10))) },
+        ];
+
+        for case in cases {
+            let db_nodes = case
+                .db
+                .into_iter()
+                .map(|(online, pending_secs)| models::NodeQuery {
+                    id: "node".to_owned(),
+                    name: "name".to_owned(),
+                    online,
+                    pending_since: pending_secs.and_then(ago),
+                })
+                .collect();
+            let cur_nodes = case
+                .listed
+                .into_iter()
+                .map(|(online, name)| {
+                    let data = NodeData {
+                        name: name.to_owned(),
+                        state: NodeState::from_online(online),
+                        details: NodeDetails::default(),
+                    };
+                    ("node".to_owned(), data)
+                })
+                .collect();
+            let flap_suppress = chrono::Duration::seconds(case.flap_suppress_secs);
+            let (events, changes) = diff_nodes(db_nodes, cur_nodes, |_| false, flap_suppress, now);
+            let events: Vec<_> = events
+                .into_iter()
+                .map(|event| {
+                    assert_eq!(event.node_id, "node", "{}", case.name);
+                    (event.from, event.to)
+                })
+                .collect();
+            assert_eq!(events, Vec::from_iter(case.event), "{}", case.name);
+            assert_eq!(changes, Vec::from_iter(case.change), "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn diff_nodes_leaves_skipped_nodes_alone() {
+        let db_nodes = vec![models::NodeQuery {
+            id: "other:node".to_owned(),
+            name: "name".to_owned(),
+            online: true,
+            pending_since: None,
+        }];
+        let skip = |id: &str| source_name(id) == Some("other");
+        let (events, changes) = diff_nodes(
+            db_nodes,
+            HashMap::new(),
+            skip,
+            chrono::Duration::zero(),
+            Utc::now(),
+        );
+        assert!(events.is_empty());
+        assert!(changes.is_empty());
     }
 }
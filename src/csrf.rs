@@ -0,0 +1,110 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+
+use anyhow::anyhow;
+use rocket::{
+    data::{self, ByteUnit, Data, FromData},
+    form::{Form, FromForm},
+    http::{RawStr, Status},
+    outcome::Outcome,
+    Request,
+};
+
+use crate::config::Config;
+use crate::token::{Purpose, SignedToken};
+use crate::util::ClientIp;
+
+/// The name of the form field carrying the token
+const FIELD: &str = "csrf";
+
+/// The largest form we accept unless `limits.csrf-form` says otherwise.  Our forms have a handful
+/// of short fields, so there is no reason to read more from anyone.
+const DEFAULT_LIMIT: ByteUnit = ByteUnit::Kibibyte(4);
+
+/// Issue a token that lets the client with the given IP address submit a form
+pub fn token(config: &Config, client_ip: Option<IpAddr>) -> String {
+    config
+        .secrets
+        .signing_keys
+        .sign((Purpose::Csrf, client_ip), config.ui.get_token_validity())
+        .encode()
+}
+
+fn verify(config: &Config, client_ip: Option<IpAddr>, token: &str) -> anyhow::Result<()> {
+    let token = SignedToken::<(Purpose, Option<IpAddr>)>::decode(token)?;
+    let (purpose, token_ip) = config.secrets.signing_keys.verify(token)?;
+    if purpose != Purpose::Csrf || token_ip != client_ip {
+        anyhow::bail!("token was not issued for this form or client");
+    }
+    Ok(())
+}
+
+/// A data guard that parses a form like `Form<T>`, but fails with 403 unless the form also
+/// contains a valid token issued by `token` to the same client.  Bodies that are not a URL-encoded
+/// form fail with 415, and bodies larger than the limit with 413.
+pub struct CsrfForm<T>(T);
+
+impl<T> CsrfForm<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T> FromData<'r> for CsrfForm<T>
+where
+    T: for<'a> FromForm<'a> + Send + 'static,
+{
+    type Error = anyhow::Error;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        if !req.content_type().is_some_and(|ty| ty.is_form()) {
+            return Outcome::Error((
+                Status::UnsupportedMediaType,
+                anyhow!("expected an `application/x-www-form-urlencoded` body"),
+            ));
+        }
+        let limit = req.limits().get("csrf-form").unwrap_or(DEFAULT_LIMIT);
+        let body = match data.open(limit).into_string().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                return Outcome::Error((Status::PayloadTooLarge, anyhow!("form is too large")))
+            }
+            Err(e) => return Outcome::Error((Status::BadRequest, e.into())),
+        };
+
+        let config = req.rocket().state::<Config>().unwrap();
+        let token = Form::values(&body).find(|field| field.name == FIELD);
+        let verified = match token {
+            Some(token) => RawStr::new(token.value)
+                .url_decode()
+                .map_err(anyhow::Error::from)
+                .and_then(|token| verify(config, ClientIp::of(req).0, &token)),
+            None => Err(anyhow!("form has no `{}` field", FIELD)),
+        };
+        if let Err(e) = verified {
+            return Outcome::Error((Status::Forbidden, e));
+        }
+
+        // Browsers percent-encode the values, e.g. the `@` of email addresses
+        match Form::<T>::parse_encoded(RawStr::new(&body)) {
+            Ok(value) => Outcome::Success(CsrfForm(value)),
+            Err(e) => Outcome::Error((Status::UnprocessableEntity, anyhow!("{}", e))),
+        }
+    }
+}
@@ -1,46 +1,185 @@
-use std::{ops::Deref, str::FromStr as _};
+use std::{
+    fmt,
+    ops::Deref,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 use lettre::{
-    message::{header::ContentType, Mailbox},
+    address::Envelope,
+    message::{
+        header::{ContentType, Header, HeaderName, HeaderValue},
+        Mailbox, MultiPart,
+    },
+    transport::smtp::{
+        self,
+        authentication::Credentials,
+        client::{Tls, TlsParameters},
+        extension::ClientId,
+    },
+    transport::{file::AsyncFileTransport, stub::AsyncStubTransport},
     Address, AsyncSmtpTransport, AsyncTransport as _, Message, Tokio1Executor,
 };
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::{Deserialize, Serialize};
 
 use rocket::{
     form::{self, FromFormField},
-    UriDisplayQuery,
+    tokio, UriDisplayQuery,
 };
 use rocket_dyn_templates::Template;
 
-use crate::config::Config;
-use crate::util::Ctx;
+use crate::config::{Config, MailTransport, SmtpSecurity};
+use crate::metrics::Metrics;
+use crate::util::{Ctx, RequestId};
 
-/// Type for email addresses in Rocket forms
-#[derive(Clone, Serialize, Deserialize, UriDisplayQuery)]
+/// Type for validated email addresses.  The domain part is normalized to lower case.
+///
+/// This checks a subset of RFC 5321 addresses, not full RFC 5322 syntax: the user part is either
+/// free of `@` and not starting with `"`, or a non-empty quoted string like `"john@doe"`; either
+/// way it must not contain `,`.  Comments, folding whitespace and address literals like
+/// `[192.0.2.1]` are not supported.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, UriDisplayQuery)]
 pub struct EmailAddress(String);
 
 impl EmailAddress {
     pub fn new<'e>(s: String) -> form::Result<'e, EmailAddress> {
-        let email_parts: Vec<&str> = s.split('@').collect();
-        if email_parts.len() != 2 {
-            return Err(rocket::form::Error::validation("invalid credit card number").into());
+        // Limits from RFC 5321, section 4.5.3.1
+        const MAX_LOCAL_LEN: usize = 64;
+        const MAX_DOMAIN_LEN: usize = 255;
+        const MAX_LEN: usize = 254;
+
+        fn check_dots<'e>(part: &str, name: &str) -> form::Result<'e, ()> {
+            if part.starts_with('.') || part.ends_with('.') {
+                return Err(form::Error::validation(format!(
+                    "{} part must not start or end with .",
+                    name
+                ))
+                .into());
+            }
+            if part.contains("..") {
+                return Err(form::Error::validation(format!(
+                    "{} part must not contain consecutive dots",
+                    name
+                ))
+                .into());
+            }
+            Ok(())
+        }
+
+        /// The length of the quoted string `s` starts with, including the quotes.  Inside the
+        /// quotes, any printable character may appear, and `\` escapes the character after it.
+        fn quoted_len<'e>(s: &str) -> form::Result<'e, usize> {
+            let mut chars = s.char_indices().skip(1);
+            while let Some((i, c)) = chars.next() {
+                let c = match c {
+                    '\\' => match chars.next() {
+                        Some((_, c)) => c,
+                        None => break,
+                    },
+                    '"' => return Ok(i + 1),
+                    c => c,
+                };
+                if c.is_control() {
+                    return Err(form::Error::validation(
+                        "User part must not contain control characters",
+                    )
+                    .into());
+                }
+            }
+            Err(form::Error::validation("User part has an unterminated quote").into())
+        }
+
+        // The user part is either quoted as a whole, and then may contain `@` and other special
+        // characters, or it must not contain `@` at all.  Either way, the domain part follows
+        // after the next `@`.
+        let quoted = s.starts_with('"');
+        let local_len = if quoted {
+            quoted_len(&s)?
+        } else {
+            s.find('@').unwrap_or(s.len())
+        };
+        let (local, rest) = s.split_at(local_len);
+        let domain = match rest.strip_prefix('@') {
+            Some(domain) if !domain.contains('@') => domain,
+            None if quoted && !rest.is_empty() => {
+                return Err(
+                    form::Error::validation("Quoted user part must be followed by @").into(),
+                )
+            }
+            _ => return Err(form::Error::validation("Address must contain exactly one @").into()),
+        };
+        // Internationalized domains are stored in their punycode form.  Domains are
+        // case-insensitive, so we store them in lower case to avoid treating the same mailbox as
+        // two different addresses; the local part is left alone as it may be case-sensitive.
+        let domain = if domain.is_ascii() {
+            domain.to_ascii_lowercase()
+        } else {
+            idna::domain_to_ascii(domain)
+                .map_err(|_| form::Error::validation("Domain part is not a valid domain name"))?
+        };
+        let domain = domain.as_str();
+        if local.is_empty() || local == "\"\"" {
+            return Err(form::Error::validation("User part is empty").into());
         }
-        if email_parts[0].is_empty() {
-            return Err(rocket::form::Error::validation("User part is empty").into());
+        // The outbox keeps the recipients of an email as a comma-separated list
+        if local.contains(',') {
+            return Err(form::Error::validation("User part must not contain ,").into());
         }
-        if email_parts[1].find('.').is_none() {
-            return Err(rocket::form::Error::validation("Domain part must contain .").into());
+        if local.len() > MAX_LOCAL_LEN {
+            return Err(form::Error::validation("User part is too long").into());
         }
-        Ok(EmailAddress(s))
+        if domain.len() > MAX_DOMAIN_LEN {
+            return Err(form::Error::validation("Domain part is too long").into());
+        }
+        if local.len() + 1 + domain.len() > MAX_LEN {
+            return Err(form::Error::validation("Address is too long").into());
+        }
+        if !quoted {
+            check_dots(local, "User")?;
+        }
+        check_dots(domain, "Domain")?;
+        if domain.find('.').is_none() {
+            return Err(form::Error::validation("Domain part must contain .").into());
+        }
+        Ok(EmailAddress(format!("{}@{}", local, domain)))
+    }
+
+    /// The user part and the domain part of the address.  Only a quoted user part can contain
+    /// `@`, so the domain part is what follows the last one.
+    pub fn split_at_sign(&self) -> (&str, &str) {
+        self.0.rsplit_once('@').expect("validated email address")
+    }
+
+    /// The address with the domain decoded from punycode, for display purposes
+    pub fn to_unicode(&self) -> String {
+        let (local, domain) = self.split_at_sign();
+        let (domain, _) = idna::domain_to_unicode(domain);
+        format!("{}@{}", local, domain)
+    }
+}
+
+impl FromStr for EmailAddress {
+    type Err = form::Errors<'static>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `new` does address validation
+        EmailAddress::new(s.to_owned())
     }
 }
 
 #[rocket::async_trait]
 impl<'r> FromFormField<'r> for EmailAddress {
     fn from_value(field: form::ValueField<'r>) -> form::Result<'r, Self> {
-        // `new` does address validation
-        EmailAddress::new(String::from_value(field)?)
+        // Like `from_str`, but keeping the errors tied to the form
+        EmailAddress::new(field.value.to_owned())
+    }
+}
+
+impl fmt::Display for EmailAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
@@ -52,48 +191,602 @@ impl Deref for EmailAddress {
     }
 }
 
+/// The final outcome of an email that could not be sent
+#[derive(Debug)]
+pub enum SendError {
+    /// The SMTP host could not be reached or asked us to try again later, even after retrying
+    Transient(smtp::Error),
+    /// The SMTP host rejected the email
+    Permanent(smtp::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Transient(e) => write!(f, "sending email failed temporarily: {}", e),
+            SendError::Permanent(e) => write!(f, "sending email failed permanently: {}", e),
+        }
+    }
+}
+
+impl SendError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, SendError::Transient(_))
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::Transient(e) | SendError::Permanent(e) => Some(e),
+        }
+    }
+}
+
+/// Why an email could not be built from its template
+#[derive(Debug)]
+pub enum TemplateError {
+    /// The template does not exist or failed to render, e.g. because it uses a value that is not
+    /// there.  The details from Handlebars are logged by `rocket_dyn_templates`.
+    Render { template: String },
+    /// The rendered template lacks a part of the email
+    Malformed {
+        template: String,
+        missing: &'static str,
+    },
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Render { template } => {
+                write!(f, "failed to render email template `{}`", template)
+            }
+            TemplateError::Malformed { template, missing } => {
+                write!(
+                    f,
+                    "malformed email template `{}`: missing {}",
+                    template, missing
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Whether an SMTP error is worth retrying: 4xx replies and connection problems are.
+fn is_transient(e: &smtp::Error) -> bool {
+    e.is_transient() || !(e.is_permanent() || e.is_response() || e.is_client() || e.is_tls())
+}
+
+/// The `List-Unsubscribe` header
+#[derive(Clone)]
+struct ListUnsubscribe(String);
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(ListUnsubscribe(s.to_owned()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), self.0.clone())
+    }
+}
+
+/// The `List-Unsubscribe-Post` header, announcing one-click unsubscription
+#[derive(Clone)]
+struct ListUnsubscribePost;
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        if s.trim() != "List-Unsubscribe=One-Click" {
+            return Err(format!("unexpected List-Unsubscribe-Post value: {}", s).into());
+        }
+        Ok(ListUnsubscribePost)
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), "List-Unsubscribe=One-Click".to_owned())
+    }
+}
+
+/// Split a rendered email template into the From line, the Subject line and the body.  On
+/// failure, returns which of them is missing.
+///
+/// The first line is the From line and the second the Subject line; neither may be empty, so a
+/// template must not render a blank line before them (its license comment is a standalone tag
+/// and renders to nothing).  Everything after the second line break is the body, which may be
+/// empty but needs that line break.  Lines may end in `\r\n`; the `\r` is not part of the headers.
+fn split_email_text(text: &str) -> std::result::Result<(&str, &str, &str), &'static str> {
+    let mut parts = text.splitn(3, '\n');
+    let mut header_line = |name| match parts.next().map(|l| l.trim_end_matches('\r')) {
+        Some(line) if !line.is_empty() => Ok(line),
+        _ => Err(name),
+    };
+    let from = header_line("From line")?;
+    let subject = header_line("Subject line")?;
+    let body = parts.next().ok_or("body")?;
+    Ok((from, subject, body))
+}
+
+/// If the email body contains a line `--- HTML ---`, split it into the plain text part before
+/// that line and the HTML part after it.
+fn split_html_body(body: &str) -> Option<(&str, &str)> {
+    const SEPARATOR: &str = "--- HTML ---";
+    let mut line_start = 0;
+    for line in body.split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']) == SEPARATOR {
+            return Some((&body[..line_start], &body[line_start + line.len()..]));
+        }
+        line_start += line.len();
+    }
+    None
+}
+
+/// The HTML part of a rendered email template, if it has one
+pub fn html_part(email_text: &str) -> Option<&str> {
+    let (_, _, body) = split_email_text(email_text).ok()?;
+    split_html_body(body).map(|(_, html)| html)
+}
+
+/// Where emails are handed to
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+    Stub(AsyncStubTransport),
+    /// Log the email instead
+    DryRun,
+}
+
+/// Managed state pacing the emails handed to the SMTP host according to `smtp_max_per_minute`.
+/// This is a token bucket holding up to a minute's worth of emails, shared by everything that
+/// sends emails.
+#[derive(Default)]
+pub struct SendThrottle(tokio::sync::Mutex<Bucket>);
+
+#[derive(Default)]
+struct Bucket {
+    tokens: f64,
+    /// When `tokens` was last updated; `None` means the bucket is full
+    updated: Option<Instant>,
+}
+
+impl SendThrottle {
+    /// Wait until the next email may be sent.  The lock is held while waiting, so emails go out
+    /// in the order they asked.
+    async fn acquire(&self, per_minute: u32, request_id: RequestId) {
+        let mut bucket = self.0.lock().await;
+        let rate = f64::from(per_minute) / 60.0;
+        let capacity = f64::from(per_minute);
+        let mut now = Instant::now();
+        let tokens = match bucket.updated {
+            None => capacity,
+            Some(updated) => {
+                (bucket.tokens + now.duration_since(updated).as_secs_f64() * rate).min(capacity)
+            }
+        };
+        bucket.tokens = if tokens >= 1.0 {
+            tokens - 1.0
+        } else {
+            let wait = Duration::from_secs_f64((1.0 - tokens) / rate);
+            log::info!("[{}] throttling emails, waiting {:?}", request_id, wait);
+            tokio::time::sleep(wait).await;
+            now = Instant::now();
+            0.0
+        };
+        bucket.updated = Some(now);
+    }
+}
+
+/// A connection to the transport that is reused for all emails sent through it
+pub struct Mailer<'r> {
+    transport: Transport,
+    config: &'r Config,
+    metrics: &'r Metrics,
+    throttle: &'r SendThrottle,
+    pub(crate) request_id: RequestId,
+}
+
+impl Mailer<'_> {
+    /// Check that the SMTP host is reachable
+    pub async fn test_connection(&self) -> Result<bool> {
+        match &self.transport {
+            Transport::Smtp(transport) => Ok(transport.test_connection().await?),
+            Transport::File(_) | Transport::Stub(_) | Transport::DryRun => Ok(true),
+        }
+    }
+
+    /// Send a single email
+    pub async fn send(&self, message: Message) -> Result<()> {
+        self.send_raw(message.envelope(), &message.formatted())
+            .await
+    }
+
+    /// Send an already formatted email.  If `archive_bcc` is set, a copy goes there afterwards.
+    /// As that copy has its own envelope, the recipients cannot see it, and failing to deliver it
+    /// does not fail the email.
+    pub async fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<()> {
+        let result = self.transmit(envelope, email).await;
+        self.metrics.count_email(result.is_ok());
+        if let (Ok(()), Some(archive)) = (&result, &self.config.ui.archive_bcc) {
+            let archived = async {
+                let envelope = Envelope::new(envelope.from().cloned(), vec![archive.clone()])?;
+                self.transmit(&envelope, email).await
+            };
+            if let Err(e) = archived.await {
+                log::warn!(
+                    "[{}] failed to send archive copy of email: {:#}",
+                    self.request_id,
+                    e
+                );
+            }
+        }
+        result
+    }
+
+    async fn transmit(&self, envelope: &Envelope, email: &[u8]) -> Result<()> {
+        match &self.transport {
+            Transport::Smtp(transport) => self.deliver(transport, envelope, email).await,
+            Transport::File(transport) => transport
+                .send_raw(envelope, email)
+                .await
+                .map(|_| ())
+                .context("writing email to file failed"),
+            Transport::Stub(transport) => Ok(transport.send_raw(envelope, email).await?),
+            Transport::DryRun => {
+                log::info!(
+                    "[{}] dry run, not sending email to {}:\n{}",
+                    self.request_id,
+                    envelope
+                        .to()
+                        .iter()
+                        .map(|a| a.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    String::from_utf8_lossy(email)
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Record how long handing a message to the SMTP host took
+    fn observe_send(&self, started: Instant) {
+        let elapsed = started.elapsed();
+        self.metrics.observe_smtp_send(elapsed);
+        log::debug!(
+            "[{}] handing email to the SMTP host took {:?}",
+            self.request_id,
+            elapsed
+        );
+    }
+
+    /// Hand a message to the SMTP host, retrying transient failures
+    async fn deliver(
+        &self,
+        transport: &AsyncSmtpTransport<Tokio1Executor>,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> Result<()> {
+        if let Some(per_minute) = self.config.secrets.smtp_max_per_minute {
+            self.throttle.acquire(per_minute, self.request_id).await;
+        }
+        let retries = self.config.secrets.get_smtp_retries();
+        let mut backoff = Duration::from_secs(1);
+        let mut attempt = 0;
+        // Not counting the time we waited for the throttle
+        let started = Instant::now();
+        let r = loop {
+            match transport.send_raw(envelope, email).await {
+                Ok(r) => break r,
+                Err(e) if !is_transient(&e) => {
+                    self.observe_send(started);
+                    return Err(SendError::Permanent(e).into());
+                }
+                Err(e) if attempt >= retries => {
+                    self.observe_send(started);
+                    return Err(SendError::Transient(e).into());
+                }
+                Err(e) => {
+                    log::warn!(
+                        "[{}] transient error sending email, retrying in {:?}: {}",
+                        self.request_id,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        };
+        self.observe_send(started);
+        if !r.is_positive() {
+            bail!(
+                "sending email failed:\n{}",
+                r.first_line().unwrap_or("<no message>")
+            );
+        }
+        Ok(())
+    }
+}
+
 impl<'r> Ctx<'r> {
+    /// A new `Message-ID` in the domain we send from.  Besides random bytes to make it unique, it
+    /// contains the request ID and the template, so that an email (e.g. one stuck in the outbox)
+    /// can be matched with the log of the request that created it.
+    fn message_id(&self, template: &str) -> Result<String> {
+        let mut random = [0u8; 8];
+        SystemRandom::new()
+            .fill(&mut random)
+            .map_err(|_| anyhow!("failed to generate Message-ID"))?;
+        Ok(format!(
+            "<{}.{}.{}@{}>",
+            self.request_id(),
+            hex::encode(random),
+            template,
+            self.config().ui.email_from.domain()
+        ))
+    }
+
+    /// Set up the configured transport.  For SMTP, this does not connect yet.
+    fn build_transport(&self) -> Result<Transport> {
+        let secrets = &self.config().secrets;
+        if secrets.dry_run {
+            return Ok(Transport::DryRun);
+        }
+        match secrets.get_mail_transport() {
+            MailTransport::Smtp => {}
+            MailTransport::File => {
+                let dir = secrets
+                    .mail_dir
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("`mail_transport = \"file\"` requires `mail_dir`"))?;
+                return Ok(Transport::File(AsyncFileTransport::new(dir)));
+            }
+            MailTransport::Stub => return Ok(Transport::Stub(AsyncStubTransport::new_ok())),
+        }
+        let smtp_host = secrets.get_smtp_host();
+        let tls = match secrets.get_smtp_security() {
+            SmtpSecurity::None => Tls::None,
+            SmtpSecurity::Starttls => Tls::Required(TlsParameters::new(smtp_host.to_owned())?),
+            SmtpSecurity::Tls => Tls::Wrapper(TlsParameters::new(smtp_host.to_owned())?),
+        };
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(smtp_host)
+            .port(secrets.get_smtp_port())
+            .tls(tls);
+        if let Some(helo_name) = &secrets.smtp_helo_name {
+            builder = builder.hello_name(ClientId::Domain(helo_name.clone()));
+        }
+        match (&secrets.smtp_user, &secrets.smtp_password) {
+            (Some(user), Some(password)) => {
+                builder = builder.credentials(Credentials::new(user.clone(), password.clone()));
+                if let Some(mechanism) = secrets.smtp_auth_mechanism {
+                    builder = builder.authentication(vec![mechanism]);
+                }
+            }
+            (None, None) => {}
+            _ => bail!("`smtp_user` and `smtp_password` must either both be set or both be unset"),
+        }
+        Ok(Transport::Smtp(builder.build()))
+    }
+
+    /// Set up the mail transport
+    pub fn mailer(&self) -> Result<Mailer<'_>> {
+        Ok(Mailer {
+            transport: self.build_transport()?,
+            config: self.config(),
+            metrics: self.metrics(),
+            throttle: self.state::<SendThrottle>().unwrap(),
+            request_id: self.request_id(),
+        })
+    }
+
     /// Build an email from a template and send it
     pub async fn email(
         &self,
-        email_template: &'static str,
+        email_template: &str,
+        lang: Option<&str>,
         vals: serde_json::Value,
         to: &str,
     ) -> Result<()> {
+        let message = self.build_email(email_template, lang, vals, to, None)?;
+        self.mailer()?.send(message).await
+    }
+
+    /// Render the best email template with the given name for `lang`.  Returns the name of the
+    /// template that was used, and the text from which `build_email` takes headers and body.
+    pub fn render_email(
+        &self,
+        email_template: &str,
+        lang: Option<&str>,
+        vals: serde_json::Value,
+    ) -> Result<(String, String)> {
+        let email_template = self
+            .find_template(email_template, lang)
+            .unwrap_or_else(|| email_template.to_owned());
+        let started = Instant::now();
+        let email_text = Template::show(
+            self,
+            email_template.clone(),
+            self.config().template_vals(vals)?,
+        )
+        .ok_or_else(|| TemplateError::Render {
+            template: email_template.clone(),
+        })?;
+        let elapsed = started.elapsed();
+        self.metrics().observe_template_render(elapsed);
+        log::debug!(
+            "[{}] rendered template {} in {:?}",
+            self.request_id(),
+            email_template,
+            elapsed
+        );
+        Ok((email_template, email_text))
+    }
+
+    /// Build an email from a template, in the given language (see `find_template`).  If an
+    /// unsubscribe URL is given, the email gets a header telling the mail client how the recipient
+    /// can unsubscribe (RFC 2369 and RFC 8058).
+    pub fn build_email(
+        &self,
+        email_template: &str,
+        lang: Option<&str>,
+        vals: serde_json::Value,
+        to: &str,
+        unsubscribe_url: Option<&str>,
+    ) -> Result<Message> {
         let config = self.state::<Config>().unwrap();
-        let email_text = Template::show(self, email_template, config.template_vals(vals)?).unwrap();
-        let email_parts: Vec<&str> = email_text.splitn(3, '\n').collect();
+        let (email_template, email_text) = self.render_email(email_template, lang, vals)?;
         let (email_from, email_subject, email_body) =
-            (email_parts[0], email_parts[1], email_parts[2]);
+            split_email_text(&email_text).map_err(|missing| TemplateError::Malformed {
+                template: email_template.clone(),
+                missing,
+            })?;
 
         // Build email
-        let message = Message::builder()
-            .from(Mailbox::new(
-                Some(email_from.to_owned()),
-                config.ui.email_from.clone(),
-            ))
-            .to(Address::from_str(to)?.into())
-            .subject(email_subject)
-            .header(ContentType::TEXT_PLAIN)
-            .body(email_body.to_owned())
-            .unwrap();
-
-        // Send email
-        let smtp_host = config.secrets.get_smtp_host();
-        let mailer = if smtp_host == "localhost" {
-            AsyncSmtpTransport::unencrypted_localhost() // always port 25
+        let from = if email_from.contains('<') {
+            // The template specifies a full `Name <address>` mailbox
+            Mailbox::from_str(email_from)?
         } else {
-            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?
-                .port(25)
-                .build()
+            Mailbox::new(Some(email_from.to_owned()), config.ui.email_from.clone())
         };
-        let r = mailer.send(message).await?;
-        if !r.is_positive() {
-            bail!(
-                "sending email failed:\n{}",
-                r.first_line().unwrap_or("<no message>")
-            );
+        let mut builder = Message::builder()
+            .message_id(Some(self.message_id(&email_template)?))
+            .date_now()
+            .from(from)
+            .to(Address::from_str(to)?.into())
+            .subject(config.ui.prefixed_subject(email_subject));
+        if let Some(reply_to) = &config.ui.reply_to {
+            builder = builder.reply_to(reply_to.clone().into());
         }
-        Ok(())
+        if let Some(unsubscribe_url) = unsubscribe_url {
+            builder = builder
+                .header(ListUnsubscribe(format!("<{}>", unsubscribe_url)))
+                .header(ListUnsubscribePost);
+        }
+        let mut message = match split_html_body(email_body) {
+            Some((text, html)) => builder.multipart(MultiPart::alternative_plain_html(
+                text.to_owned(),
+                html.to_owned(),
+            ))?,
+            None => builder
+                .header(ContentType::TEXT_PLAIN)
+                .body(email_body.to_owned())?,
+        };
+        if let Some(dkim) = &config.dkim {
+            message.sign(dkim.signing_config());
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::Ui;
+
+    fn valid(s: &str) -> String {
+        EmailAddress::new(s.to_owned())
+            .unwrap_or_else(|e| panic!("{} rejected: {}", s, e))
+            .to_string()
+    }
+
+    fn invalid(s: &str) {
+        assert!(EmailAddress::new(s.to_owned()).is_err(), "{} accepted", s);
+    }
+
+    #[test]
+    fn quoted_user_parts() {
+        assert_eq!(
+            valid(r#""john.doe"@example.com"#),
+            r#""john.doe"@example.com"#
+        );
+        assert_eq!(valid(r#""a@b"@example.com"#), r#""a@b"@example.com"#);
+        assert_eq!(valid(r#""a\"b"@x.org"#), r#""a\"b"@x.org"#);
+        invalid(r#""a@x.org"#);
+        invalid(r#""a"b@x.org"#);
+        invalid("a@b@x.org");
+        invalid(r#""a,b"@example.org"#);
+        invalid("a,b@example.org");
+        invalid(r#"""@example.org"#);
+    }
+
+    #[test]
+    fn at_sign_in_quoted_user_part() {
+        let email = EmailAddress::new(r#""a@b"@Example.COM"#.to_owned()).unwrap();
+        assert_eq!(email.split_at_sign(), (r#""a@b""#, "example.com"));
+
+        let ui = |local_parts: &[&str], domains: &[&str]| -> Ui {
+            serde_json::from_value(serde_json::json!({
+                "instance_name": "Freifunk Test",
+                "instance_article_dative": "der",
+                "email_from": "monitor@example.org",
+                "blocked_local_parts": local_parts,
+                "blocked_domains": domains,
+            }))
+            .unwrap()
+        };
+        assert!(ui(&[r#""a@b""#], &[]).is_blocked(&email));
+        assert!(ui(&[], &["example.com"]).is_blocked(&email));
+        // What splitting at the first `@` would yield
+        assert!(!ui(&[r#""a"#], &[r#"b"@example.com"#]).is_blocked(&email));
+    }
+
+    #[test]
+    fn split_well_formed_email_text() {
+        assert_eq!(
+            split_email_text("From: a@example.org\nSubject: Hi\n\nHello\nthere\n"),
+            Ok(("From: a@example.org", "Subject: Hi", "\nHello\nthere\n"))
+        );
+        // The body may be empty, but the line break before it is needed
+        assert_eq!(
+            split_email_text("From: a@example.org\nSubject: Hi\n"),
+            Ok(("From: a@example.org", "Subject: Hi", ""))
+        );
+    }
+
+    #[test]
+    fn split_email_text_with_leading_blank_line() {
+        assert_eq!(
+            split_email_text("\nFrom: a@example.org\nSubject: Hi\n\nHello\n"),
+            Err("From line")
+        );
+        assert_eq!(
+            split_email_text("\r\nFrom: a@example.org\nSubject: Hi\n\nHello\n"),
+            Err("From line")
+        );
+    }
+
+    #[test]
+    fn split_email_text_with_crlf() {
+        assert_eq!(
+            split_email_text("From: a@example.org\r\nSubject: Hi\r\n\r\nHello\r\n"),
+            Ok(("From: a@example.org", "Subject: Hi", "\r\nHello\r\n"))
+        );
+    }
+
+    #[test]
+    fn split_email_text_with_too_few_lines() {
+        assert_eq!(split_email_text(""), Err("From line"));
+        assert_eq!(split_email_text("From: a@example.org"), Err("Subject line"));
+        assert_eq!(
+            split_email_text("From: a@example.org\n"),
+            Err("Subject line")
+        );
+        assert_eq!(
+            split_email_text("From: a@example.org\nSubject: Hi"),
+            Err("body")
+        );
     }
 }
@@ -0,0 +1,177 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Handlebars helpers for formatting timestamps in templates.  Both take an RFC 3339 timestamp
+//! (and render nothing for `null`):
+//!
+//! * `{{format_time ts}}` shows it like `14.10.2026 08:15`, in the configured `timezone`.  The
+//!   hash parameters `format` (a chrono format string) and `tz` (an IANA time zone name)
+//!   override that.
+//! * `{{relative_time ts}}` shows it relative to now, like `vor 3 Stunden`, in `default_lang`.
+//!   The hash parameter `lang` overrides that; we know `de` and `en`.
+
+use std::fmt::Write as _;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rocket_dyn_templates::handlebars::{
+    Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError,
+};
+
+/// How `format_time` formats timestamps by default
+const DEFAULT_FORMAT: &str = "%d.%m.%Y %H:%M";
+
+pub fn register(handlebars: &mut Handlebars<'_>) {
+    handlebars.register_helper("format_time", Box::new(format_time));
+    handlebars.register_helper("relative_time", Box::new(relative_time));
+}
+
+/// The timestamp given as the first parameter of the helper `name`, or `None` if it is `null`
+fn timestamp(h: &Helper<'_, '_>, name: &str) -> Result<Option<DateTime<Utc>>, RenderError> {
+    let value = h
+        .param(0)
+        .ok_or_else(|| RenderError::new(format!("{}: missing timestamp", name)))?
+        .value();
+    if value.is_null() {
+        return Ok(None);
+    }
+    let text = value
+        .as_str()
+        .ok_or_else(|| RenderError::new(format!("{}: the timestamp must be a string", name)))?;
+    let timestamp = DateTime::parse_from_rfc3339(text).map_err(|_| {
+        RenderError::new(format!("{}: `{}` is not an RFC 3339 timestamp", name, text))
+    })?;
+    Ok(Some(timestamp.with_timezone(&Utc)))
+}
+
+/// The hash parameter `key` of the helper, or else the `ui` setting `setting` from the context
+fn setting(h: &Helper<'_, '_>, ctx: &Context, key: &str, setting: &str) -> Option<String> {
+    let value = match h.hash_get(key) {
+        Some(value) => value.value(),
+        None => ctx.data().get("config")?.get("ui")?.get(setting)?,
+    };
+    value.as_str().map(str::to_owned)
+}
+
+fn format_time(
+    h: &Helper<'_, '_>,
+    _: &Handlebars<'_>,
+    ctx: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let Some(timestamp) = timestamp(h, "format_time")? else {
+        return Ok(());
+    };
+    let tz = match setting(h, ctx, "tz", "timezone") {
+        Some(tz) => tz
+            .parse::<Tz>()
+            .map_err(|_| RenderError::new(format!("format_time: unknown time zone `{}`", tz)))?,
+        None => Tz::UTC,
+    };
+    let format = h
+        .hash_get("format")
+        .and_then(|format| format.value().as_str())
+        .unwrap_or(DEFAULT_FORMAT);
+    // An invalid format string only shows up when formatting
+    let mut formatted = String::new();
+    write!(formatted, "{}", timestamp.with_timezone(&tz).format(format)).map_err(|_| {
+        RenderError::new(format!("format_time: invalid format string `{}`", format))
+    })?;
+    out.write(&formatted)?;
+    Ok(())
+}
+
+fn relative_time(
+    h: &Helper<'_, '_>,
+    _: &Handlebars<'_>,
+    ctx: &Context,
+    _: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let Some(timestamp) = timestamp(h, "relative_time")? else {
+        return Ok(());
+    };
+    let german = setting(h, ctx, "lang", "default_lang")
+        .as_deref()
+        .unwrap_or("de")
+        == "de";
+    out.write(&describe_offset(Utc::now() - timestamp, german))?;
+    Ok(())
+}
+
+/// Describe how long ago (or, if negative, how far in the future) something is, in the largest
+/// unit that fits
+fn describe_offset(offset: chrono::Duration, german: bool) -> String {
+    let past = offset >= chrono::Duration::zero();
+    let secs = offset.num_seconds().unsigned_abs();
+    if secs < 60 {
+        return if german { "gerade eben" } else { "just now" }.to_owned();
+    }
+    // (seconds, German singular, German dative plural, English singular)
+    let units = [
+        (24 * 60 * 60, "Tag", "Tagen", "day"),
+        (60 * 60, "Stunde", "Stunden", "hour"),
+        (60, "Minute", "Minuten", "minute"),
+    ];
+    let (unit_secs, de_one, de_many, en) = units
+        .into_iter()
+        .find(|&(unit_secs, ..)| secs >= unit_secs)
+        .unwrap();
+    let count = secs / unit_secs;
+    let unit = match (german, count) {
+        (true, 1) => de_one.to_owned(),
+        (true, _) => de_many.to_owned(),
+        (false, 1) => en.to_owned(),
+        (false, _) => format!("{}s", en),
+    };
+    match (german, past) {
+        (true, true) => format!("vor {} {}", count, unit),
+        (true, false) => format!("in {} {}", count, unit),
+        (false, true) => format!("{} {} ago", count, unit),
+        (false, false) => format!("in {} {}", count, unit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    #[test]
+    fn describe_offsets() {
+        for (offset, german, english) in [
+            (Duration::zero(), "gerade eben", "just now"),
+            (Duration::seconds(59), "gerade eben", "just now"),
+            (Duration::seconds(-59), "gerade eben", "just now"),
+            (Duration::seconds(60), "vor 1 Minute", "1 minute ago"),
+            (Duration::seconds(119), "vor 1 Minute", "1 minute ago"),
+            (Duration::minutes(5), "vor 5 Minuten", "5 minutes ago"),
+            (Duration::hours(1), "vor 1 Stunde", "1 hour ago"),
+            (Duration::minutes(150), "vor 2 Stunden", "2 hours ago"),
+            (Duration::days(1), "vor 1 Tag", "1 day ago"),
+            (Duration::days(30), "vor 30 Tagen", "30 days ago"),
+            (Duration::minutes(-1), "in 1 Minute", "in 1 minute"),
+            (Duration::hours(-3), "in 3 Stunden", "in 3 hours"),
+            (Duration::days(-1), "in 1 Tag", "in 1 day"),
+            (Duration::days(-2), "in 2 Tagen", "in 2 days"),
+        ] {
+            assert_eq!(describe_offset(offset, true), german, "{}", offset);
+            assert_eq!(describe_offset(offset, false), english, "{}", offset);
+        }
+    }
+}
@@ -15,25 +15,119 @@
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 mod action;
+mod admin;
+mod cli;
 mod config;
 mod cron;
+mod csrf;
 mod db;
 mod email;
+mod helpers;
+mod maintenance;
+mod metrics;
 mod models;
+mod notify;
+mod outbox;
+mod quiet;
+mod rate_limit;
 mod routes;
 mod schema;
+mod suppression;
+mod token;
+mod upstream;
 mod util;
 
-#[rocket::launch]
-fn rocket() -> _ {
-    // Launch the rocket (also initializes `log` facade)
-    rocket::build()
+use std::future::Future;
+use std::process;
+use std::time::Duration;
+
+use rocket::{figment::Figment, Build, Rocket};
+
+/// The table in `Rocket.toml` that holds our configuration
+const CONFIG_SECTION: &str = "ff-node-monitor";
+
+/// What is needed to render and send emails
+fn base(figment: Figment) -> Rocket<Build> {
+    rocket::custom(figment)
+        .attach(config::fairing(CONFIG_SECTION))
+        .manage(metrics::Metrics::default())
+        .manage(email::SendThrottle::default())
+        .attach(util::templates())
+}
+
+/// The web application
+fn server(figment: Figment) -> Rocket<Build> {
+    base(figment)
+        .attach(util::RequestIds)
+        .attach(maintenance::MaintenanceMode)
         .attach(db::DbConn::fairing())
         .attach(db::migration())
-        .attach(config::fairing("ff-node-monitor"))
-        .attach(rocket_dyn_templates::Template::custom(|engines| {
-            engines.handlebars.set_strict_mode(true);
-        }))
+        .manage(rate_limit::RateLimiter::default())
+        .manage(cron::NodesCache::default())
+        .manage(cron::CircuitBreakers::default())
+        .manage(cron::StaleSources::default())
+        .manage(cron::RecentEvents::default())
+        .manage(cron::UpdateLock::default())
+        .manage(util::BackgroundTasks::default())
+        .attach(util::BackgroundTasks::fairing())
+        .attach(outbox::fairing())
+        .attach(cron::fairing())
         .mount("/static", rocket::fs::FileServer::from("static"))
         .mount("/", routes::routes())
+        .register("/", routes::catchers())
+}
+
+/// Run `future` on an async runtime set up like `#[rocket::main]` does it, but from the given
+/// configuration; `#[rocket::main]` can only read it from a TOML file.
+fn execute(figment: &Figment, future: impl Future<Output = ()>) {
+    let config = match rocket::Config::try_from(figment) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    let runtime = rocket::tokio::runtime::Builder::new_multi_thread()
+        .thread_name("rocket-worker-thread")
+        .worker_threads(config.workers)
+        .max_blocking_threads(config.max_blocking)
+        .enable_all()
+        .build()
+        .expect("failed to create the async runtime");
+    runtime.block_on(future);
+    if config.shutdown.force {
+        runtime.shutdown_timeout(Duration::from_millis(500));
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = args.first().map(String::as_str);
+    match command {
+        None | Some("send-test") => {}
+        Some("--check-config") => return cli::check_config(&args[1..]),
+        Some(command) => {
+            eprintln!(
+                "unknown command `{}`; available are `send-test` and `--check-config`",
+                command
+            );
+            process::exit(2);
+        }
+    }
+    let figment = match config::figment() {
+        Ok(figment) => figment,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            process::exit(1);
+        }
+    };
+    execute(&figment, async {
+        match command {
+            // Launch the rocket (also initializes `log` facade)
+            None => {
+                let _ = server(figment.clone()).launch().await;
+            }
+            _ => cli::send_test(figment.clone(), &args[1..]).await,
+        }
+    });
 }
@@ -0,0 +1,97 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Maintenance mode, in which only the health check, the metrics, the admin routes and the
+//! background jobs are served
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    http::{uri::Origin, Method},
+    Build, Data, Request, Rocket,
+};
+
+use crate::config::Config;
+
+/// Where requests end up while the service is in maintenance mode
+pub const MAINTENANCE_PATH: &str = "/maintenance";
+
+/// The original path of a request that maintenance mode sent to `MAINTENANCE_PATH`, kept in the
+/// request-local cache
+pub struct DivertedFrom(pub Option<String>);
+
+/// The paths that are still served in maintenance mode, with everything below them.  `/cron` and
+/// `/outbox` are requested by the background workers (and external cron jobs), which would
+/// otherwise fail on every run.
+const EXEMPT_PATHS: &[&str] = &[
+    "/health", "/metrics", "/admin", "/static", "/cron", "/outbox",
+];
+
+/// Managed state telling whether the service is in maintenance mode
+pub struct Maintenance(AtomicBool);
+
+impl Maintenance {
+    pub fn is_on(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, on: bool) {
+        self.0.store(on, Ordering::Relaxed)
+    }
+}
+
+/// A fairing that manages the `Maintenance` state, starting out as `maintenance_mode` says, and
+/// while it is on, sends all requests to paths that are not exempt to `MAINTENANCE_PATH`.
+pub struct MaintenanceMode;
+
+#[rocket::async_trait]
+impl Fairing for MaintenanceMode {
+    fn info(&self) -> Info {
+        Info {
+            name: "Maintenance mode",
+            kind: Kind::Ignite | Kind::Request,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let on = rocket
+            .state::<Config>()
+            .is_some_and(|config| config.ui.get_maintenance_mode());
+        Ok(rocket.manage(Maintenance(AtomicBool::new(on))))
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(maintenance) = request.rocket().state::<Maintenance>() else {
+            return;
+        };
+        if !maintenance.is_on() {
+            return;
+        }
+        let path = request.uri().path();
+        let exempt = EXEMPT_PATHS.iter().any(|exempt| {
+            path.as_str()
+                .strip_prefix(exempt)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        if !exempt {
+            let path = path.to_string();
+            request.local_cache(|| DivertedFrom(Some(path)));
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(MAINTENANCE_PATH).unwrap());
+        }
+    }
+}
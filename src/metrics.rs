@@ -0,0 +1,158 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::notify::Direction;
+use crate::util::Ctx;
+
+/// The upper bounds (in seconds) of the buckets of the duration histograms
+const DURATION_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+/// How long something took, in buckets that each count the durations up to their bound
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Managed state holding the counters exposed at `/metrics`
+#[derive(Default)]
+pub struct Metrics {
+    emails_sent: AtomicU64,
+    emails_failed: AtomicU64,
+    transitions_online: AtomicU64,
+    transitions_offline: AtomicU64,
+    updates_skipped: AtomicU64,
+    template_render: Histogram,
+    smtp_send: Histogram,
+}
+
+impl Metrics {
+    pub fn count_email(&self, success: bool) {
+        let counter = if success {
+            &self.emails_sent
+        } else {
+            &self.emails_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_transition(&self, direction: Direction) {
+        let counter = match direction {
+            Direction::Up => &self.transitions_online,
+            Direction::Down => &self.transitions_offline,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an update of the nodes that was skipped because the previous one was still running
+    pub fn count_skipped_update(&self) {
+        self.updates_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long rendering an email template took
+    pub fn observe_template_render(&self, duration: Duration) {
+        self.template_render.observe(duration);
+    }
+
+    /// Record how long handing an email to the SMTP host took, including retries
+    pub fn observe_smtp_send(&self, duration: Duration) {
+        self.smtp_send.observe(duration);
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, values: &[(&str, &AtomicU64)]| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for (labels, value) in values {
+                writeln!(out, "{}{} {}", name, labels, value.load(Ordering::Relaxed)).unwrap();
+            }
+        };
+        counter(
+            "ffmon_emails_sent_total",
+            "Number of emails sent successfully.",
+            &[("", &self.emails_sent)],
+        );
+        counter(
+            "ffmon_emails_failed_total",
+            "Number of emails that could not be sent.",
+            &[("", &self.emails_failed)],
+        );
+        counter(
+            "ffmon_node_transitions_total",
+            "Number of times a node went online or offline.",
+            &[
+                ("{direction=\"online\"}", &self.transitions_online),
+                ("{direction=\"offline\"}", &self.transitions_offline),
+            ],
+        );
+        counter(
+            "ffmon_updates_skipped_total",
+            "Number of node updates skipped because the previous one was still running.",
+            &[("", &self.updates_skipped)],
+        );
+        let mut histogram = |name: &str, help: &str, histogram: &Histogram| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} histogram", name).unwrap();
+            for (bound, bucket) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+                let value = bucket.load(Ordering::Relaxed);
+                writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, value).unwrap();
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+            writeln!(out, "{}_sum {}", name, sum).unwrap();
+            writeln!(out, "{}_count {}", name, count).unwrap();
+        };
+        histogram(
+            "ffmon_template_render_seconds",
+            "Time spent rendering email templates.",
+            &self.template_render,
+        );
+        histogram(
+            "ffmon_smtp_send_seconds",
+            "Time spent handing emails to the SMTP host, including retries.",
+            &self.smtp_send,
+        );
+        out
+    }
+}
+
+impl Ctx<'_> {
+    pub fn metrics(&self) -> &Metrics {
+        self.state::<Metrics>().unwrap()
+    }
+}
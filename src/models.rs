@@ -14,15 +14,27 @@
 //  You should have received a copy of the GNU Affero General Public License
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use chrono::{DateTime, NaiveTime, Utc};
 use diesel::prelude::*;
 use serde::Serialize;
 
 use crate::schema::*;
 
-#[derive(Queryable, Serialize)]
+#[derive(Queryable, Serialize, Clone)]
 pub struct MonitorQuery {
     pub id: String,
     pub email: String,
+    pub backend: String,
+    pub quiet_start: Option<NaiveTime>,
+    pub quiet_end: Option<NaiveTime>,
+    pub timezone: Option<String>,
+    pub notify_mode: String,
+    /// `None` means the default language
+    pub lang: Option<String>,
+    /// `None` for monitors that were created before we kept track of this
+    pub created_at: Option<DateTime<Utc>>,
+    /// Whether the node is part of the weekly summary for this address
+    pub weekly_summary: bool,
 }
 
 #[derive(Insertable, Identifiable)]
@@ -30,6 +42,14 @@ pub struct MonitorQuery {
 pub struct Monitor<'a> {
     pub id: &'a str,
     pub email: &'a str,
+    pub backend: &'a str,
+    pub quiet_start: Option<NaiveTime>,
+    pub quiet_end: Option<NaiveTime>,
+    pub timezone: Option<&'a str>,
+    pub notify_mode: &'a str,
+    pub lang: Option<&'a str>,
+    pub created_at: DateTime<Utc>,
+    pub weekly_summary: bool,
 }
 
 #[derive(Queryable, Serialize)]
@@ -37,6 +57,8 @@ pub struct NodeQuery {
     pub id: String,
     pub name: String,
     pub online: bool,
+    /// Since when the node has been in a different state than `online`, if it has
+    pub pending_since: Option<DateTime<Utc>>,
 }
 
 #[derive(Queryable, Serialize)]
@@ -53,4 +75,154 @@ pub struct Node<'a> {
     pub id: &'a str,
     pub name: &'a str,
     pub online: bool,
+    pub pending_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable)]
+pub struct QueuedNotification<'a> {
+    pub id: &'a str,
+    pub email: &'a str,
+    /// The state the node went to
+    pub online: bool,
+    pub deliver_at: DateTime<Utc>,
+    /// The monitor this is for; differs from `id` if the monitor covers several nodes
+    pub monitor_id: &'a str,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = digest_entries)]
+pub struct DigestEntry<'a> {
+    pub email: &'a str,
+    pub node_id: &'a str,
+    pub name: &'a str,
+    /// The state the node went to
+    pub online: bool,
+    pub at: DateTime<Utc>,
+    pub deliver_at: DateTime<Utc>,
+    /// The monitor this is for; differs from `node_id` if the monitor covers several nodes
+    pub monitor_id: &'a str,
+}
+
+#[derive(Queryable)]
+#[allow(unused)] // we don't actually need all these fields
+pub struct DigestEntryQuery {
+    pub seq: i32,
+    pub email: String,
+    pub node_id: String,
+    pub name: String,
+    pub online: bool,
+    pub at: DateTime<Utc>,
+    pub deliver_at: DateTime<Utc>,
+    pub monitor_id: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = outbox)]
+pub struct OutboxEntry<'a> {
+    pub sender: &'a str,
+    /// Comma-separated
+    pub recipients: &'a str,
+    /// The formatted email
+    pub message: &'a [u8],
+    pub created_at: DateTime<Utc>,
+    /// When whoever is currently delivering this email started doing so
+    pub claimed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Queryable)]
+#[allow(unused)] // we don't actually need all these fields
+pub struct OutboxQuery {
+    pub seq: i32,
+    pub sender: String,
+    pub recipients: String,
+    pub message: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+    pub claimed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = pending_actions)]
+pub struct PendingAction<'a> {
+    pub email: &'a str,
+    pub node: &'a str,
+    pub node_name: &'a str,
+    /// The action as JSON
+    pub action: &'a str,
+    /// When the last confirmation email was sent
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Queryable)]
+#[allow(unused)] // we don't actually need all these fields
+pub struct PendingActionQuery {
+    pub email: String,
+    pub node: String,
+    pub node_name: String,
+    pub action: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = node_history)]
+pub struct HistoryEntry<'a> {
+    pub node_id: &'a str,
+    /// The state the node went to
+    pub online: bool,
+    pub at: DateTime<Utc>,
+}
+
+/// The state we last notified the watchers of a node about
+#[derive(Insertable)]
+#[diesel(table_name = notified_states)]
+pub struct NotifiedState<'a> {
+    pub node_id: &'a str,
+    pub online: bool,
+    pub notified_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = suppressions)]
+pub struct Suppression<'a> {
+    pub email: &'a str,
+    /// Why nothing may be sent there, e.g. "bounce" or "complaint"
+    pub reason: Option<&'a str>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct SuppressionQuery {
+    pub email: String,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = idempotency_keys)]
+pub struct IdempotentRequest<'a> {
+    pub key: &'a str,
+    /// A SHA-256 hash of the body of the request, to detect a key being reused for another
+    /// request.  The body itself is not kept as it contains an email address.
+    pub request_hash: &'a str,
+    /// `None` while the request is being processed
+    pub status: Option<i16>,
+    pub response: Option<&'a str>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Queryable)]
+#[allow(unused)] // we don't actually need all these fields
+pub struct IdempotentRequestQuery {
+    pub key: String,
+    pub request_hash: String,
+    pub status: Option<i16>,
+    pub response: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// When the next weekly summary for an address is due
+#[derive(Insertable)]
+#[diesel(table_name = weekly_summaries)]
+pub struct WeeklySummary<'a> {
+    pub email: &'a str,
+    pub deliver_at: DateTime<Utc>,
 }
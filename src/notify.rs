@@ -0,0 +1,581 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use lettre::Message;
+use reqwest::header::CONTENT_TYPE;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use rocket::{tokio, uri, FromFormField};
+use rocket_dyn_templates::Template;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::action::{Action, Operation};
+use crate::config::{Config, Matrix, Webhook};
+use crate::db::DbConn;
+use crate::email::{EmailAddress, Mailer};
+use crate::routes;
+use crate::util::{BackgroundTasks, Ctx};
+
+/// Whether a node is reachable
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeState {
+    Online,
+    Offline,
+}
+
+/// In which direction a node's state changed
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+impl NodeState {
+    pub fn from_online(online: bool) -> Self {
+        if online {
+            NodeState::Online
+        } else {
+            NodeState::Offline
+        }
+    }
+
+    pub fn is_online(self) -> bool {
+        self == NodeState::Online
+    }
+
+    /// The direction of the change from `old` to `new`, or `None` if the state did not change
+    pub fn transition(old: NodeState, new: NodeState) -> Option<Direction> {
+        match (old, new) {
+            (NodeState::Offline, NodeState::Online) => Some(Direction::Up),
+            (NodeState::Online, NodeState::Offline) => Some(Direction::Down),
+            _ => None,
+        }
+    }
+}
+
+impl Direction {
+    /// The state the node was in before the change
+    pub fn old_state(self) -> NodeState {
+        match self {
+            Direction::Up => NodeState::Offline,
+            Direction::Down => NodeState::Online,
+        }
+    }
+
+    /// The state the node is in after the change
+    pub fn new_state(self) -> NodeState {
+        match self {
+            Direction::Up => NodeState::Online,
+            Direction::Down => NodeState::Offline,
+        }
+    }
+}
+
+/// What the node list says about a node, beyond its name and state.  Every part may be missing.
+#[derive(Clone, Default)]
+pub struct NodeDetails {
+    pub last_seen: Option<DateTime<Utc>>,
+    pub uptime_secs: Option<f64>,
+    pub firmware: Option<String>,
+    /// The owner's contact information
+    pub contact: Option<String>,
+}
+
+/// A change of a node's online status
+pub struct NodeEvent {
+    pub node_id: String,
+    pub name: String,
+    pub from: NodeState,
+    /// Always differs from `from`
+    pub to: NodeState,
+    /// When we noticed the change
+    pub at: DateTime<Utc>,
+    pub details: NodeDetails,
+}
+
+impl NodeEvent {
+    /// Whether the node came up or went down
+    pub fn direction(&self) -> Direction {
+        match self.to {
+            NodeState::Online => Direction::Up,
+            NodeState::Offline => Direction::Down,
+        }
+    }
+
+    /// The values for the templates.  Details that are not known are `null`, so templates can
+    /// check for them with `{{#if}}`.
+    fn template_vals(&self, config: &Config) -> serde_json::Value {
+        let tz = config.ui.get_timezone();
+        let details = &self.details;
+        json!({
+            "node": {
+                "id": self.node_id,
+                "name": self.name,
+                "online": self.to.is_online(),
+                "last_seen": details.last_seen.map(|last_seen| {
+                    last_seen.with_timezone(&tz).format("%d.%m.%Y %H:%M").to_string()
+                }),
+                "last_seen_at": details.last_seen.map(|last_seen| last_seen.to_rfc3339()),
+                "uptime": details.uptime_secs.map(format_duration),
+                "firmware": details.firmware,
+                "contact": details.contact,
+            },
+        })
+    }
+}
+
+/// Format a number of seconds like `3d 4h 12m`
+fn format_duration(secs: f64) -> String {
+    let minutes = (secs.max(0.0) / 60.0) as u64;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+/// Where the notifications for a monitor are delivered
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Email,
+    Matrix,
+}
+
+impl Backend {
+    /// The representation of the backend in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Backend::Email => "email",
+            Backend::Matrix => "matrix",
+        }
+    }
+
+    pub fn from_db(s: &str) -> Result<Self> {
+        Ok(match s {
+            "email" => Backend::Email,
+            "matrix" => Backend::Matrix,
+            _ => bail!("unknown notification backend: {}", s),
+        })
+    }
+}
+
+/// When the notifications for a monitor are delivered
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Default, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyMode {
+    /// As soon as possible; events of several nodes noticed at the same time are combined into
+    /// one email
+    #[default]
+    Immediate,
+    /// One notification per event, as soon as possible
+    Separate,
+    /// One email per day summarizing all events
+    Digest,
+}
+
+impl NotifyMode {
+    /// The representation of the mode in the database
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotifyMode::Immediate => "immediate",
+            NotifyMode::Separate => "separate",
+            NotifyMode::Digest => "digest",
+        }
+    }
+
+    pub fn from_db(s: &str) -> Result<Self> {
+        Ok(match s {
+            "immediate" => NotifyMode::Immediate,
+            "separate" => NotifyMode::Separate,
+            "digest" => NotifyMode::Digest,
+            _ => bail!("unknown notification mode: {}", s),
+        })
+    }
+}
+
+/// Someone monitoring a node
+pub struct Watcher {
+    pub email: String,
+    /// `None` means the default language
+    pub lang: Option<String>,
+    /// The pattern through which the watcher monitors the node, if they do not monitor it
+    /// directly
+    pub pattern: Option<String>,
+}
+
+/// Something that can tell people about node events
+#[rocket::async_trait]
+pub trait Notifier: Sync {
+    /// Notify `watchers` about `event`
+    async fn notify(&self, event: &NodeEvent, watchers: &[Watcher]) -> Result<()>;
+}
+
+impl Ctx<'_> {
+    /// The values for the notification email about `event` to `watcher`, and the URL that stops
+    /// these notifications
+    pub fn notification_vals(
+        &self,
+        event: &NodeEvent,
+        watcher: &Watcher,
+    ) -> Result<(serde_json::Value, String)> {
+        let config = self.config();
+        let email = watcher
+            .email
+            .parse::<EmailAddress>()
+            .map_err(|e| anyhow!("{}", e))?;
+        let list_url = config.urls.absolute(uri!(routes::list(email = &email)));
+        let unsubscribe_url = routes::action_url(
+            config,
+            Action {
+                node: watcher
+                    .pattern
+                    .clone()
+                    .unwrap_or_else(|| event.node_id.clone()),
+                email: email.clone(),
+                op: Operation::Remove,
+                backend: Backend::Email,
+                quiet_hours: None,
+                notify_mode: NotifyMode::Immediate,
+                lang: watcher.lang.clone(),
+                weekly_summary: false,
+                force: false,
+            },
+        );
+        let unsubscribe_all_url = routes::unsubscribe_all_url(config, email);
+        let mut vals = event.template_vals(config);
+        let obj = vals.as_object_mut().unwrap();
+        obj.insert("list_url".to_owned(), json!(list_url));
+        obj.insert(
+            "unsubscribe_url".to_owned(),
+            json!(unsubscribe_url.as_str()),
+        );
+        obj.insert("unsubscribe_all_url".to_owned(), json!(unsubscribe_all_url));
+        Ok((vals, unsubscribe_url))
+    }
+
+    /// The template for emails about the given event: `node_up` or `node_down` if the
+    /// installation has them in `lang`, in the default language or without a language (see
+    /// `find_template`), and `notification` otherwise
+    pub fn notification_template(&self, event: &NodeEvent, lang: Option<&str>) -> &'static str {
+        let template = match event.direction() {
+            Direction::Up => "node_up",
+            Direction::Down => "node_down",
+        };
+        if self.find_template(template, lang).is_some() {
+            template
+        } else {
+            "notification"
+        }
+    }
+
+    /// The message posted to Matrix about `event`
+    pub fn matrix_text(&self, event: &NodeEvent) -> Result<String> {
+        let config = self.config();
+        Template::show(
+            self,
+            "notification_matrix",
+            config.template_vals(event.template_vals(config))?,
+        )
+        .ok_or_else(|| anyhow!("failed to render template `notification_matrix`"))
+    }
+
+    /// The values for the email telling `watcher` about all the given events at once, and the URL
+    /// that stops all of the watcher's notifications
+    pub fn combined_notification_vals(
+        &self,
+        events: &[&NodeEvent],
+        watcher: &Watcher,
+    ) -> Result<(serde_json::Value, String)> {
+        let config = self.config();
+        let email = watcher
+            .email
+            .parse::<EmailAddress>()
+            .map_err(|e| anyhow!("{}", e))?;
+        let list_url = config.urls.absolute(uri!(routes::list(email = &email)));
+        let unsubscribe_all_url = routes::unsubscribe_all_url(config, email);
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| event.template_vals(config))
+            .collect();
+        let vals = json!({
+            "events": events,
+            "list_url": list_url,
+            "unsubscribe_all_url": unsubscribe_all_url,
+        });
+        Ok((vals, unsubscribe_all_url))
+    }
+}
+
+/// Sends notifications via email, one to each watcher
+pub struct EmailNotifier<'r> {
+    ctx: &'r Ctx<'r>,
+    db: &'r DbConn,
+    mailer: Mailer<'r>,
+}
+
+impl<'r> EmailNotifier<'r> {
+    pub fn new(ctx: &'r Ctx<'r>, db: &'r DbConn) -> Result<Self> {
+        Ok(EmailNotifier {
+            ctx,
+            db,
+            mailer: ctx.mailer()?,
+        })
+    }
+
+    fn build_email(&self, event: &NodeEvent, watcher: &Watcher) -> Result<Message> {
+        let lang = watcher.lang.as_deref();
+        let (vals, unsubscribe_url) = self.ctx.notification_vals(event, watcher)?;
+        self.ctx.build_email(
+            self.ctx.notification_template(event, lang),
+            lang,
+            vals,
+            &watcher.email,
+            Some(&unsubscribe_url),
+        )
+    }
+
+    /// Build a single email telling the watcher about all the given events.  As it is about
+    /// several nodes, its `List-Unsubscribe` header removes all of the watcher's monitors.
+    fn build_combined_email(&self, events: &[&NodeEvent], watcher: &Watcher) -> Result<Message> {
+        let (vals, unsubscribe_all_url) = self.ctx.combined_notification_vals(events, watcher)?;
+        self.ctx.build_email(
+            "notifications",
+            watcher.lang.as_deref(),
+            vals,
+            &watcher.email,
+            Some(&unsubscribe_all_url),
+        )
+    }
+
+    /// Notify each watcher about its events, combining them into one email for watchers with
+    /// several events
+    pub async fn notify_combined(&self, batches: &[(Watcher, Vec<&NodeEvent>)]) -> Result<()> {
+        let emails = batches
+            .iter()
+            .map(|(watcher, events)| match events[..] {
+                [event] => self.build_email(event, watcher),
+                _ => self.build_combined_email(events, watcher),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let total = emails.len();
+        let failed = self.send(emails).await?;
+        if failed > 0 {
+            bail!("failed to send {} of {} notifications", failed, total);
+        }
+        log::info!(
+            "[{}] sent {} combined email notifications",
+            self.ctx.request_id(),
+            total
+        );
+        Ok(())
+    }
+
+    /// Send the emails and return how many of them failed
+    async fn send(&self, emails: Vec<Message>) -> Result<usize> {
+        let mut failed = 0;
+        for result in self.mailer.send_all_durably(self.db, emails).await? {
+            if let Err(e) = result {
+                log::error!(
+                    "[{}] failed to send notification: {:#}",
+                    self.ctx.request_id(),
+                    e
+                );
+                failed += 1;
+            }
+        }
+        Ok(failed)
+    }
+}
+
+#[rocket::async_trait]
+impl Notifier for EmailNotifier<'_> {
+    async fn notify(&self, event: &NodeEvent, watchers: &[Watcher]) -> Result<()> {
+        let emails = watchers
+            .iter()
+            .map(|watcher| self.build_email(event, watcher))
+            .collect::<Result<Vec<_>>>()?;
+        let total = emails.len();
+        let failed = self.send(emails).await?;
+        if failed > 0 {
+            bail!("failed to send {} of {} notifications", failed, total);
+        }
+        log::info!(
+            "[{}] sent {} email notifications about {}",
+            self.ctx.request_id(),
+            total,
+            event.node_id
+        );
+        Ok(())
+    }
+}
+
+/// Posts notifications to a Matrix room.  The room gets one message per event, no matter how many
+/// watchers chose Matrix; the watchers' addresses are not posted.
+pub struct MatrixNotifier<'r> {
+    ctx: &'r Ctx<'r>,
+    matrix: &'r Matrix,
+    client: reqwest::Client,
+}
+
+impl<'r> MatrixNotifier<'r> {
+    pub fn new(ctx: &'r Ctx<'r>, matrix: &'r Matrix) -> Self {
+        MatrixNotifier {
+            ctx,
+            matrix,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Notifier for MatrixNotifier<'_> {
+    async fn notify(&self, event: &NodeEvent, _watchers: &[Watcher]) -> Result<()> {
+        let text = self.ctx.matrix_text(event)?;
+
+        // Every message needs a unique transaction ID
+        let mut txn_id = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut txn_id)
+            .map_err(|_| anyhow!("failed to generate transaction ID"))?;
+        let mut url = self.matrix.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid Matrix homeserver URL"))?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3", "rooms"])
+            .push(&self.matrix.room_id)
+            .extend(["send", "m.room.message"])
+            .push(&hex::encode(txn_id));
+
+        self.client
+            .put(url)
+            .bearer_auth(&self.matrix.access_token)
+            .json(&json!({
+                "msgtype": "m.text",
+                "body": text.trim(),
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        log::info!(
+            "[{}] posted notification about {} to Matrix",
+            self.ctx.request_id(),
+            event.node_id
+        );
+        Ok(())
+    }
+}
+
+/// POSTs every event as JSON to a webhook.  Delivery happens in the background so that a slow or
+/// unreachable receiver does not hold up the other notifications; failures are only logged.
+pub struct WebhookNotifier<'r> {
+    ctx: &'r Ctx<'r>,
+    webhook: &'r Webhook,
+    client: reqwest::Client,
+}
+
+impl<'r> WebhookNotifier<'r> {
+    /// The header carrying the signature of the request body
+    const SIGNATURE_HEADER: &'static str = "X-FF-Node-Monitor-Signature";
+
+    pub fn new(ctx: &'r Ctx<'r>, webhook: &'r Webhook) -> Self {
+        WebhookNotifier {
+            ctx,
+            webhook,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Notifier for WebhookNotifier<'_> {
+    async fn notify(&self, event: &NodeEvent, _watchers: &[Watcher]) -> Result<()> {
+        let body = serde_json::to_vec(&json!({
+            "node_id": event.node_id,
+            "name": event.name,
+            "old_state": event.from,
+            "new_state": event.to,
+            "timestamp": event.at.to_rfc3339(),
+        }))?;
+        let signature = if self.webhook.sign {
+            let key = self.ctx.config().secrets.signing_keys.derive("webhook");
+            Some(format!(
+                "sha256={}",
+                hex::encode(hmac::sign(&key, &body).as_ref())
+            ))
+        } else {
+            None
+        };
+
+        let client = self.client.clone();
+        let url = self.webhook.url.clone();
+        let retries = self.webhook.get_retries();
+        let node_id = event.node_id.clone();
+        let request_id = self.ctx.request_id();
+        let tasks = self.ctx.state::<BackgroundTasks>().unwrap();
+        tasks.spawn(async move {
+            let mut backoff = Duration::from_secs(1);
+            let mut attempt = 0;
+            loop {
+                let mut request = client
+                    .post(url.clone())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.clone());
+                if let Some(signature) = &signature {
+                    request = request.header(Self::SIGNATURE_HEADER, signature);
+                }
+                let e = match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => {
+                        log::info!("[{}] called webhook for {}", request_id, node_id);
+                        break;
+                    }
+                    Err(e) => e,
+                };
+                // Client errors will not go away by trying again
+                let permanent = e.status().is_some_and(|s| s.is_client_error());
+                if permanent || attempt >= retries {
+                    log::error!(
+                        "[{}] failed to call webhook for {}: {}",
+                        request_id,
+                        node_id,
+                        e
+                    );
+                    break;
+                }
+                log::warn!(
+                    "[{}] error calling webhook for {}, retrying in {:?}: {}",
+                    request_id,
+                    node_id,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        });
+        Ok(())
+    }
+}
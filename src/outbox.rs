@@ -0,0 +1,298 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Notifications are written to the outbox table before they are sent, and removed once they got
+//! delivered (or rejected for good).  That way, a notification that could not be delivered due to
+//! a temporary problem, or because the process died while sending it, is retried later instead of
+//! getting lost.
+//!
+//! Every row is claimed by whoever is delivering it, so that two concurrent cron runs never send
+//! the same email twice.  Claims only get released after a temporary failure; rows still claimed
+//! at startup were abandoned by a previous process.
+
+use std::str::FromStr as _;
+
+use anyhow::{anyhow, Context as _, Result};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use lettre::{address::Envelope, Address, Message};
+use rocket::fairing::{AdHoc, Fairing};
+use rocket::uri;
+
+use crate::db::DbConn;
+use crate::email::{Mailer, SendError};
+use crate::models;
+use crate::routes;
+use crate::schema::*;
+use crate::suppression;
+use crate::util::{self, BackgroundTasks, Ctx};
+
+/// Which rows to pick up when retrying
+#[derive(Clone, Copy)]
+enum Retry {
+    /// Only rows that nobody is working on
+    Unclaimed,
+    /// All rows, including those claimed by a previous process
+    All,
+}
+
+impl Mailer<'_> {
+    /// Write the messages to the outbox, then deliver them.  Returns one result per message, in
+    /// the same order.  Messages that only go to suppressed addresses are dropped (which counts
+    /// as success).
+    pub async fn send_all_durably(
+        &self,
+        db: &DbConn,
+        messages: Vec<Message>,
+    ) -> Result<Vec<Result<()>>> {
+        let recipients = messages
+            .iter()
+            .flat_map(|message| message.envelope().to().iter().map(|a| a.to_string()))
+            .collect();
+        let suppressed = suppression::suppressed(db, recipients).await?;
+        // Whether to send each message, so that the results can be put in the right order
+        let send: Vec<bool> = messages
+            .iter()
+            .map(|message| {
+                let recipients: Vec<String> = message
+                    .envelope()
+                    .to()
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect();
+                if recipients.iter().all(|a| suppressed.contains(a)) {
+                    log::info!(
+                        "[{}] not sending email to suppressed address {}",
+                        self.request_id,
+                        recipients.join(", ")
+                    );
+                    return false;
+                }
+                true
+            })
+            .collect();
+
+        let now = Utc::now();
+        let rows: Vec<OutboxMessage> = messages
+            .iter()
+            .zip(&send)
+            .filter(|(_, &send)| send)
+            .map(|(message, _)| OutboxMessage::new(message))
+            .collect();
+        let entries = db
+            .run(move |db| {
+                let rows: Vec<_> = rows.iter().map(|row| row.entry(now, true)).collect();
+                diesel::insert_into(outbox::table)
+                    .values(&rows)
+                    .get_results::<models::OutboxQuery>(db)
+            })
+            .await?;
+
+        let mut entries = entries.into_iter();
+        let mut results = Vec::with_capacity(send.len());
+        for send in send {
+            let entry = if send { entries.next() } else { None };
+            let result = match entry {
+                Some(entry) => self.deliver_entry(db, entry).await,
+                None => Ok(()),
+            };
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Deliver the emails that are left in the outbox, and return how many were sent
+    pub async fn retry_outbox(&self, db: &DbConn) -> Result<usize> {
+        self.retry(db, Retry::Unclaimed).await
+    }
+
+    async fn retry(&self, db: &DbConn, retry: Retry) -> Result<usize> {
+        let now = Utc::now();
+        let entries = db
+            .run(move |db| {
+                let update = diesel::update(outbox::table);
+                match retry {
+                    Retry::Unclaimed => update
+                        .filter(outbox::claimed_at.is_null())
+                        .set(outbox::claimed_at.eq(Some(now)))
+                        .get_results::<models::OutboxQuery>(db),
+                    Retry::All => update
+                        .set(outbox::claimed_at.eq(Some(now)))
+                        .get_results::<models::OutboxQuery>(db),
+                }
+            })
+            .await?;
+
+        let mut sent = 0;
+        for entry in entries {
+            match self.deliver_entry(db, entry).await {
+                Ok(()) => sent += 1,
+                Err(e) => log::error!("failed to send email from the outbox: {:#}", e),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Deliver a (claimed) row of the outbox
+    pub async fn deliver_entry(&self, db: &DbConn, entry: models::OutboxQuery) -> Result<()> {
+        // A row we cannot make sense of will not get better by retrying, so it is dropped (and
+        // the error reported) like any other permanent failure.
+        let result = match parse_envelope(&entry) {
+            Ok(envelope) => self.send_raw(&envelope, &entry.message).await,
+            Err(e) => Err(e.context("invalid envelope")),
+        };
+        let transient = match &result {
+            Ok(()) => false,
+            Err(e) => e
+                .downcast_ref::<SendError>()
+                .is_some_and(SendError::is_transient),
+        };
+        let result = result.with_context(|| match message_id(&entry.message) {
+            Some(message_id) => {
+                format!("outbox entry {} with Message-ID {}", entry.seq, message_id)
+            }
+            None => format!("outbox entry {}", entry.seq),
+        });
+        let seq = entry.seq;
+        db.run(move |db| {
+            let row = outbox::table.find(seq);
+            if transient {
+                // Release the claim so that the next run retries it
+                diesel::update(row)
+                    .set(outbox::claimed_at.eq(None::<chrono::DateTime<Utc>>))
+                    .execute(db)
+            } else {
+                diesel::delete(row).execute(db)
+            }
+        })
+        .await?;
+        result
+    }
+}
+
+/// An email in the form it is stored in the outbox
+pub struct OutboxMessage {
+    sender: String,
+    /// Separated by `,`, which `EmailAddress` does not allow in addresses
+    recipients: String,
+    message: Vec<u8>,
+}
+
+impl OutboxMessage {
+    pub fn new(message: &Message) -> Self {
+        let envelope = message.envelope();
+        let sender = envelope.from().map(|a| a.to_string()).unwrap_or_default();
+        let recipients = envelope
+            .to()
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        OutboxMessage {
+            sender,
+            recipients,
+            message: message.formatted(),
+        }
+    }
+
+    fn entry(&self, now: DateTime<Utc>, claimed: bool) -> models::OutboxEntry<'_> {
+        models::OutboxEntry {
+            sender: &self.sender,
+            recipients: &self.recipients,
+            message: &self.message,
+            created_at: now,
+            claimed_at: claimed.then_some(now),
+        }
+    }
+
+    /// Write the email to the outbox, for whoever retries the outbox next to deliver; call
+    /// `wake` afterwards to make that happen right away.  Doing this in a transaction together
+    /// with other changes makes sure that the email gets sent if and only if those changes are
+    /// made.
+    pub fn enqueue(&self, db: &mut PgConnection) -> QueryResult<()> {
+        diesel::insert_into(outbox::table)
+            .values(&self.entry(Utc::now(), false))
+            .execute(db)?;
+        Ok(())
+    }
+}
+
+/// The `Message-ID` header of a formatted email
+fn message_id(message: &[u8]) -> Option<&str> {
+    let message = std::str::from_utf8(message).ok()?;
+    message
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| line.strip_prefix("Message-ID: "))
+}
+
+fn parse_envelope(entry: &models::OutboxQuery) -> Result<Envelope> {
+    let sender = match entry.sender.as_str() {
+        "" => None,
+        sender => Some(Address::from_str(sender)?),
+    };
+    let recipients = entry
+        .recipients
+        .split(',')
+        .map(Address::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Envelope::new(sender, recipients)?)
+}
+
+/// Deliver what is in the outbox in the background, so that the caller does not wait for the SMTP
+/// host.  The background task cannot use the running instance, so it requests the `/outbox` route;
+/// should that fail, the next cron run delivers the emails.
+pub fn wake(ctx: &Ctx<'_>) {
+    let url = util::local_url(ctx, uri!(routes::outbox_route));
+    let request_id = ctx.request_id();
+    let tasks = ctx.state::<BackgroundTasks>().unwrap();
+    tasks.spawn(async move {
+        let result = reqwest::Client::new()
+            .post(&url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = result {
+            log::warn!(
+                "[{}] failed to deliver the outbox, leaving that to the next cron run: {}",
+                request_id,
+                e
+            );
+        }
+    });
+}
+
+/// A fairing that, on startup, delivers whatever a previous process left in the outbox.
+/// This delays serving requests until the outbox is empty, which is usually immediately.
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_liftoff("Deliver outbox", |rocket| {
+        Box::pin(async move {
+            let ctx = Ctx::new(rocket);
+            let result = async {
+                let db = DbConn::get_one(rocket)
+                    .await
+                    .ok_or_else(|| anyhow!("failed to get database connection"))?;
+                ctx.mailer()?.retry(&db, Retry::All).await
+            }
+            .await;
+            match result {
+                Ok(0) => {}
+                Ok(sent) => log::info!("sent {} emails left in the outbox", sent),
+                Err(e) => log::error!("failed to deliver the outbox: {:#}", e),
+            }
+        })
+    })
+}
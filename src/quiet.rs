@@ -0,0 +1,100 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::str::FromStr as _;
+
+use chrono::{DateTime, Days, Duration, NaiveTime, TimeZone as _, Utc};
+use chrono_tz::Tz;
+use rocket::form::{self, FromFormField};
+use rocket::FromForm;
+use serde::{Deserialize, Serialize};
+
+/// A time of day in a form, written `HH:MM`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct TimeOfDay(pub NaiveTime);
+
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for TimeOfDay {
+    fn from_value(field: form::ValueField<'r>) -> form::Result<'r, Self> {
+        NaiveTime::parse_from_str(field.value, "%H:%M")
+            .map(TimeOfDay)
+            .map_err(|_| form::Error::validation("Time must be given as HH:MM").into())
+    }
+}
+
+/// A time zone in a form, given by its IANA name such as `Europe/Berlin`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Timezone(pub Tz);
+
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for Timezone {
+    fn from_value(field: form::ValueField<'r>) -> form::Result<'r, Self> {
+        Tz::from_str(field.value)
+            .map(Timezone)
+            .map_err(|_| form::Error::validation("Unknown time zone").into())
+    }
+}
+
+/// A daily window, in the subscriber's time zone, during which notifications are held back
+#[derive(Serialize, Deserialize, FromForm, Clone, Copy, Debug)]
+pub struct QuietHours {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    pub timezone: Timezone,
+}
+
+impl QuietHours {
+    /// If `now` lies within the quiet hours, return when they end
+    pub fn end_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let (start, end, tz) = (self.start.0, self.end.0, self.timezone.0);
+        let local = now.with_timezone(&tz);
+        let time = local.time();
+        let quiet = if start <= end {
+            start <= time && time < end
+        } else {
+            // The window spans midnight
+            start <= time || time < end
+        };
+        if !quiet {
+            return None;
+        }
+        let mut date = local.date_naive();
+        if time >= end {
+            // The window ends tomorrow
+            date = date + Days::new(1);
+        }
+        let end = date.and_time(end);
+        // If the end falls into the gap of a DST change, the window ends an hour later
+        let end = tz.from_local_datetime(&end).earliest().or_else(|| {
+            tz.from_local_datetime(&(end + Duration::hours(1)))
+                .earliest()
+        })?;
+        Some(end.with_timezone(&Utc))
+    }
+
+    /// Reassemble quiet hours from how they are stored in the database
+    pub fn from_db(
+        start: Option<NaiveTime>,
+        end: Option<NaiveTime>,
+        timezone: Option<&str>,
+    ) -> Option<Self> {
+        Some(QuietHours {
+            start: TimeOfDay(start?),
+            end: TimeOfDay(end?),
+            timezone: Timezone(Tz::from_str(timezone?).ok()?),
+        })
+    }
+}
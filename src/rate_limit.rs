@@ -0,0 +1,75 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rocket::{
+    http::Status,
+    request::{self, FromRequest, Outcome},
+    Request,
+};
+
+use crate::config::Config;
+use crate::util::ClientIp;
+
+/// Managed state remembering recent requests per client
+#[derive(Default)]
+pub struct RateLimiter {
+    requests: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+}
+
+impl RateLimiter {
+    /// Record a request from `ip`, and return whether it is within the limit
+    fn check(&self, ip: IpAddr, config: &Config) -> bool {
+        let now = Instant::now();
+        let window = config.ui.get_rate_limit_window();
+        let mut requests = self.requests.lock().unwrap();
+        // Forget about requests that are outside the window
+        requests.retain(|_, times| {
+            times.retain(|&t| now.duration_since(t) < window);
+            !times.is_empty()
+        });
+        let times = requests.entry(ip).or_default();
+        if times.len() >= config.ui.get_rate_limit_requests() {
+            return false;
+        }
+        times.push(now);
+        true
+    }
+}
+
+/// A request guard that fails with 429 when the client sent too many requests
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(ip) = ClientIp::of(request).0 else {
+            return Outcome::Success(RateLimit);
+        };
+        let config = request.rocket().state::<Config>().unwrap();
+        let limiter = request.rocket().state::<RateLimiter>().unwrap();
+        if limiter.check(ip, config) {
+            Outcome::Success(RateLimit)
+        } else {
+            Outcome::Error((Status::TooManyRequests, ()))
+        }
+    }
+}
@@ -14,28 +14,39 @@
 //  You should have received a copy of the GNU Affero General Public License
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
-use base64::Engine as _;
+use chrono::{DateTime, SecondsFormat, Utc};
 use diesel::prelude::*;
-use rmp_serde::from_slice as deserialize_from_slice;
-use rmp_serde::to_vec as serialize_to_vec;
+use lettre::Message;
+use serde::Deserialize;
 use serde_json::json;
 
-use rocket::{form::Form, response, State};
-use rocket::{get, post, routes, uri, Request};
+use rocket::catcher::{self, Catcher};
+use rocket::data::{ByteUnit, Data};
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Outcome};
+use rocket::response::Responder as _;
+use rocket::{delete, get, post, routes, uri, FromForm, Request};
+use rocket::{response, response::content::RawJson, State};
 use rocket_dyn_templates::Template;
 
 use crate::action::*;
+use crate::admin::Admin;
 use crate::config::Config;
 use crate::cron;
+use crate::csrf::{self, CsrfForm};
 use crate::db::DbConn;
-use crate::email::EmailAddress;
+use crate::email::{self, EmailAddress};
+use crate::maintenance::{DivertedFrom, Maintenance};
 use crate::models::*;
-use crate::util::Ctx;
-
-const BASE64_ENGINE: base64::engine::GeneralPurpose =
-    base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use crate::notify::{Backend, NodeState, NotifyMode};
+use crate::outbox::OutboxMessage;
+use crate::rate_limit::RateLimit;
+use crate::suppression;
+use crate::token::{Purpose, SignedToken};
+use crate::util::{ClientIp, Ctx, PreferredLang, Prefetch};
 
 /// Custom error type to allow using `?` below.
 struct Error(anyhow::Error);
@@ -57,15 +68,92 @@ where
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Sign the given action and compute the URL that runs it
+pub fn action_url(config: &Config, action: Action) -> String {
+    let signed_action = config
+        .secrets
+        .signing_keys
+        .sign(action, config.ui.get_token_validity())
+        .encode();
+    config
+        .urls
+        .absolute(uri!(run_action(signed_action = &signed_action)))
+}
+
+/// Sign the given email address for the given purpose
+fn email_token(config: &Config, purpose: Purpose, email: EmailAddress) -> String {
+    config
+        .secrets
+        .signing_keys
+        .sign((purpose, email), config.ui.get_token_validity())
+        .encode()
+}
+
+/// Verify a token created by `email_token`, and return the email address
+fn verify_email_token(
+    config: &Config,
+    purpose: Purpose,
+    token: &str,
+) -> anyhow::Result<EmailAddress> {
+    let token = SignedToken::<(Purpose, EmailAddress)>::decode(token)?;
+    let (token_purpose, email) = config.secrets.signing_keys.verify(token)?;
+    if token_purpose != purpose {
+        anyhow::bail!("token is not valid for {:?}", purpose);
+    }
+    Ok(email)
+}
+
+/// Compute the URL that removes all monitors of the given email address
+pub fn unsubscribe_all_url(config: &Config, email: EmailAddress) -> String {
+    let token = email_token(config, Purpose::UnsubscribeAll, email);
+    config.urls.absolute(uri!(unsubscribe_all(token = &token)))
+}
+
+/// Build the email asking to confirm the given action
+fn confirmation_email(ctx: &Ctx<'_>, action: &Action, node_name: &str) -> anyhow::Result<Message> {
+    let config = ctx.config();
+    let action_url = action_url(config, action.clone());
+    let list_url = config.urls.absolute(uri!(list(email = &action.email)));
+    ctx.build_email(
+        "confirm_action",
+        action.lang.as_deref(),
+        json!({
+            "action": action,
+            "node_name": node_name,
+            "pattern": node_prefix(&action.node),
+            "action_url": action_url.as_str(),
+            "list_url": list_url.as_str(),
+        }),
+        &action.email,
+        None,
+    )
+}
+
 #[get("/")]
 fn index(ctx: Ctx<'_>) -> Result<Template> {
     Ok(ctx.template("index", json!({}))?)
 }
 
 #[get("/list?<email>")]
-async fn list(email: EmailAddress, ctx: Ctx<'_>, db: DbConn) -> Result<Template> {
+async fn list(
+    email: EmailAddress,
+    client_ip: ClientIp,
+    preferred_lang: PreferredLang,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Template> {
     use crate::schema::*;
 
+    if ctx.config().ui.is_blocked(&email) {
+        return Ok(ctx.template("list_error", json!({ "blocked": true }))?);
+    }
+    let csrf_token = csrf::token(ctx.config(), client_ip.0);
+    let tz = ctx.config().ui.get_timezone();
+    // What the form suggests for the emails about new monitors
+    let lang = preferred_lang
+        .0
+        .unwrap_or_else(|| ctx.config().ui.get_default_lang().to_owned());
+
     let vars = db
         .run::<_, anyhow::Result<_>>(move |db| {
             let watched_nodes = monitors::table
@@ -73,6 +161,7 @@ async fn list(email: EmailAddress, ctx: Ctx<'_>, db: DbConn) -> Result<Template>
                 .left_join(nodes::table.on(monitors::id.eq(nodes::id)))
                 .order_by(monitors::id)
                 .load::<MonitorNodeQuery>(db)?;
+            let nodes = nodes::table.order_by(nodes::name).load::<NodeQuery>(db)?;
             let all_nodes = {
                 let watched_node_ids: HashSet<&str> = watched_nodes
                     .iter()
@@ -80,17 +169,40 @@ async fn list(email: EmailAddress, ctx: Ctx<'_>, db: DbConn) -> Result<Template>
                     .map(|node| node.id.as_str())
                     .collect();
                 // Diesel does not support joining to a subquery so we have to do the filtering in Rust
-                nodes::table
-                    .order_by(nodes::name)
-                    .load::<NodeQuery>(db)?
-                    .into_iter()
+                nodes
+                    .iter()
                     .filter(|node| !watched_node_ids.contains(&node.id.as_ref()))
-                    .collect::<Vec<NodeQuery>>()
+                    .collect::<Vec<&NodeQuery>>()
             };
+            let watched_nodes: Vec<_> = watched_nodes
+                .iter()
+                .map(|watched| {
+                    let created = watched.monitor.created_at.map(|created_at| {
+                        created_at.with_timezone(&tz).format("%d.%m.%Y").to_string()
+                    });
+                    // For a pattern, how many of the current nodes it covers
+                    let pattern = node_prefix(&watched.monitor.id).map(|prefix| {
+                        let matching = nodes
+                            .iter()
+                            .filter(|node| node.id.starts_with(prefix))
+                            .count();
+                        json!({ "prefix": prefix, "matching": matching })
+                    });
+                    json!({
+                        "monitor": watched.monitor,
+                        "node": watched.node,
+                        "created": created,
+                        "pattern": pattern,
+                    })
+                })
+                .collect();
             Ok(json!({
                 "email": email,
+                "email_display": email.to_unicode(),
                 "watched_nodes": watched_nodes,
                 "all_nodes": all_nodes,
+                "csrf_token": csrf_token,
+                "lang": lang,
             }))
         })
         .await?;
@@ -100,32 +212,46 @@ async fn list(email: EmailAddress, ctx: Ctx<'_>, db: DbConn) -> Result<Template>
 
 #[get("/list")]
 fn list_formfail(ctx: Ctx<'_>) -> Result<Template> {
-    Ok(ctx.template("list_error", json!({}))?)
+    Ok(ctx.template("list_error", json!({ "blocked": false }))?)
 }
 
-#[post("/prepare_action", data = "<action>")]
-async fn prepare_action(
-    action: Form<Action>,
-    config: &State<Config>,
-    ctx: Ctx<'_>,
-    db: DbConn,
-) -> Result<Template> {
+/// What became of a requested action
+enum Prepared {
+    /// The address is on the block list
+    Blocked,
+    /// The node to add does not exist
+    UnknownNode,
+    /// The pattern to add is not of the form `prefix*`
+    InvalidPattern,
+    /// The confirmation email was sent, if the action would change anything
+    Done { node_name: String },
+}
+
+/// Ask for confirmation of `action`.  Both the form and the API go through this.
+async fn request_action(ctx: &Ctx<'_>, db: &DbConn, action: &mut Action) -> Result<Prepared> {
     use crate::schema::*;
 
-    let action = action.into_inner();
+    let config = ctx.config();
+    if config.ui.is_blocked(&action.email) {
+        return Ok(Prepared::Blocked);
+    }
 
-    // obtain bytes for signed action payload
-    let signed_action = action.clone().sign(&config.secrets.action_signing_key);
-    let signed_action = serialize_to_vec(&signed_action)?;
-    let signed_action = BASE64_ENGINE.encode(&signed_action);
+    // Ignore languages we do not have
+    let languages = config.ui.get_languages();
+    action.lang = action
+        .lang
+        .take()
+        .filter(|lang| languages.contains(&lang.as_str()));
 
-    // compute some URLs
-    let action_url = config
-        .urls
-        .absolute(uri!(run_action(signed_action = &signed_action)));
-    let list_url = config.urls.absolute(uri!(list(email = &action.email)));
+    if action.op == Operation::Add {
+        if let Some(prefix) = node_prefix(&action.node) {
+            if prefix.is_empty() || prefix.contains('*') {
+                return Ok(Prepared::InvalidPattern);
+            }
+        }
+    }
 
-    // obtain user-readable node name
+    // obtain user-readable node name; a pattern is its own name
     let node = action.node.clone();
     let node = db
         .run(move |db| {
@@ -137,100 +263,2017 @@ async fn prepare_action(
         .await?;
     let node_name = match node {
         Some(node) => node.name,
-        None if action.op == Operation::Remove =>
+        None if action.op == Operation::Remove || node_prefix(&action.node).is_some() =>
         // Allow removing dead nodes
         {
             action.node.clone()
         }
+        None if action.force => {
+            log::info!(
+                "[{}] node {} is not in the node list, adding it anyway",
+                ctx.request_id(),
+                action.node
+            );
+            action.node.clone()
+        }
         None => {
             // Trying to add a non-existing node. Stop this.
+            return Ok(Prepared::UnknownNode);
+        }
+    };
+
+    // Only bother the user if the action would change anything.  The response is the same either
+    // way, so that it does not tell whether this address monitors the node.
+    let monitor = db
+        .run({
+            let (node, email) = (action.node.clone(), action.email.clone());
+            move |db| {
+                monitors::table
+                    .find((node.as_str(), &*email))
+                    .first::<MonitorQuery>(db)
+                    .optional()
+            }
+        })
+        .await?;
+    let nothing_to_do = match (action.op, monitor) {
+        // Adding a node again can still change its settings
+        (Operation::Add, Some(monitor)) => action.has_settings_of(&monitor),
+        (Operation::Add, None) => false,
+        (Operation::Remove, monitor) => monitor.is_none(),
+    };
+    if nothing_to_do {
+        log::info!(
+            "[{}] not sending confirmation email for {:?} of node {}: nothing to do",
+            ctx.request_id(),
+            action.op,
+            action.node
+        );
+    } else {
+        // Remember the action and put the email into the outbox together, so that there is no
+        // confirmation email for an action we forgot about and vice versa.  The email is sent in
+        // the background: how long the SMTP host takes must not tell whether this address
+        // monitors the node.
+        let confirmation = if suppression::is_suppressed(db, &action.email).await? {
+            log::info!(
+                "[{}] not sending confirmation email to suppressed address {}",
+                ctx.request_id(),
+                action.email
+            );
+            None
+        } else {
+            let email = confirmation_email(ctx, action, &node_name)?;
+            Some(OutboxMessage::new(&email))
+        };
+        let queued = confirmation.is_some();
+        action
+            .remember_pending(db, node_name.clone(), confirmation)
+            .await?;
+        if queued {
+            crate::outbox::wake(ctx);
+        }
+        log::info!(
+            "[{}] queued confirmation email for {:?} of node {}",
+            ctx.request_id(),
+            action.op,
+            action.node
+        );
+    }
+    Ok(Prepared::Done { node_name })
+}
+
+#[post("/prepare_action", data = "<action>")]
+async fn prepare_action(
+    _rate_limit: RateLimit,
+    action: CsrfForm<Action>,
+    client_ip: ClientIp,
+    preferred_lang: PreferredLang,
+    config: &State<Config>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Template> {
+    let mut action = action.into_inner();
+    if action.lang.is_none() {
+        action.lang = preferred_lang.0;
+    }
+    let list_url = config.urls.absolute(uri!(list(email = &action.email)));
+    let node_name = match request_action(&ctx, &db, &mut action).await? {
+        Prepared::Blocked => {
+            return Ok(ctx.template("list_error", json!({ "blocked": true }))?);
+        }
+        Prepared::UnknownNode => {
+            // Everything needed to send the form again, this time with `force`
+            let mut retry_fields = json!({
+                "node": action.node,
+                "email": action.email,
+                "op": "add",
+                "backend": action.backend,
+                "notify_mode": action.notify_mode,
+                "force": "true",
+                "csrf": csrf::token(config, client_ip.0),
+            });
+            if let Some(lang) = &action.lang {
+                retry_fields["lang"] = json!(lang);
+            }
+            if action.weekly_summary {
+                retry_fields["weekly_summary"] = json!("true");
+            }
+            if let Some(quiet_hours) = action.quiet_hours {
+                retry_fields["quiet_hours.start"] =
+                    json!(quiet_hours.start.0.format("%H:%M").to_string());
+                retry_fields["quiet_hours.end"] =
+                    json!(quiet_hours.end.0.format("%H:%M").to_string());
+                retry_fields["quiet_hours.timezone"] = json!(quiet_hours.timezone.0.name());
+            }
             return Ok(ctx.template(
                 "prepare_action_error",
                 json!({
                     "action": action,
                     "list_url": list_url.as_str(),
+                    "retry_fields": retry_fields,
+                    "invalid_pattern": false,
                 }),
             )?);
         }
+        Prepared::InvalidPattern => {
+            return Ok(ctx.template(
+                "prepare_action_error",
+                json!({
+                    "action": action,
+                    "list_url": list_url.as_str(),
+                    "invalid_pattern": true,
+                }),
+            )?);
+        }
+        Prepared::Done { node_name } => node_name,
     };
 
-    // Build and send email
-    ctx.email(
-        "confirm_action",
+    // Render
+    Ok(ctx.template(
+        "prepare_action",
         json!({
             "action": action,
             "node_name": node_name,
-            "action_url": action_url.as_str(),
-            "list_url": list_url.as_str(),
+            "pattern": node_prefix(&action.node),
+            "list_url": list_url,
+            "csrf_token": csrf::token(config, client_ip.0),
         }),
-        &action.email,
-    )
-    .await?;
+    )?)
+}
 
-    // Render
+/// The largest body `api_subscribe` accepts
+const API_BODY_LIMIT: ByteUnit = ByteUnit::Kibibyte(4);
+
+#[derive(Deserialize)]
+struct Subscribe {
+    email: String,
+    node_id: String,
+    #[serde(default)]
+    lang: Option<String>,
+    /// Subscribe even if the node is not in the node list
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    weekly_summary: bool,
+}
+
+/// How long the outcome of an `api_subscribe` request with an `Idempotency-Key` is remembered
+pub(crate) const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The longest `Idempotency-Key` we accept
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+
+/// How long a request with an `Idempotency-Key` may take.  After that, we assume its processing
+/// was aborted, and a retry takes the key over instead of being told to wait.
+const IDEMPOTENCY_CLAIM_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The `Idempotency-Key` header of the request, if any
+struct IdempotencyKey(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = std::convert::Infallible;
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let key = request.headers().get_one("Idempotency-Key");
+        Outcome::Success(IdempotencyKey(key.map(str::to_owned)))
+    }
+}
+
+/// What we know about an idempotency key when a request using it comes in
+enum Claimed {
+    /// The key is new, we recorded it and the request should be processed
+    New,
+    /// The key was used for a different request
+    Mismatch,
+    /// A request with this key is still being processed
+    InProgress,
+    /// A request with this key was processed already, with this outcome
+    Done(Status, String),
+}
+
+/// What we keep of the body of a request with an `Idempotency-Key`: not the body itself, as it
+/// contains an email address, just enough to tell whether a retry has the same one
+fn request_hash(body: &str) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, body.as_bytes()))
+}
+
+/// Record that we are processing the request with the given key and body, unless that key is known
+/// already (and not left behind by a request that took longer than `IDEMPOTENCY_CLAIM_TIMEOUT`).
+async fn claim_idempotency_key(db: &DbConn, key: String, request: &str) -> Result<Claimed> {
+    use crate::schema::*;
+
+    let request_hash = request_hash(request);
+    let now = Utc::now();
+    let expired = now - chrono::Duration::from_std(IDEMPOTENCY_KEY_TTL)?;
+    let abandoned = now - chrono::Duration::from_std(IDEMPOTENCY_CLAIM_TIMEOUT)?;
+    Ok(db
+        .run(move |db| {
+            db.transaction(|db| {
+                diesel::delete(
+                    idempotency_keys::table
+                        .find(&key)
+                        .filter(idempotency_keys::created_at.lt(expired)),
+                )
+                .execute(db)?;
+                let inserted = diesel::insert_into(idempotency_keys::table)
+                    .values(&IdempotentRequest {
+                        key: &key,
+                        request_hash: &request_hash,
+                        status: None,
+                        response: None,
+                        created_at: now,
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(db)?;
+                if inserted > 0 {
+                    return Ok::<_, anyhow::Error>(Claimed::New);
+                }
+                let known = idempotency_keys::table
+                    .find(&key)
+                    .for_update()
+                    .first::<IdempotentRequestQuery>(db)?;
+                Ok(if known.request_hash != request_hash {
+                    Claimed::Mismatch
+                } else if let (Some(status), Some(response)) = (known.status, known.response) {
+                    let status = Status::from_code(status as u16).unwrap_or(Status::Ok);
+                    Claimed::Done(status, response)
+                } else if known.created_at < abandoned {
+                    diesel::update(idempotency_keys::table.find(&key))
+                        .set(idempotency_keys::created_at.eq(now))
+                        .execute(db)?;
+                    Claimed::New
+                } else {
+                    Claimed::InProgress
+                })
+            })
+        })
+        .await?)
+}
+
+/// Start monitoring a node, like the form on the list page: this sends the confirmation email.
+/// The response does not tell whether it was sent, i.e. whether the address already monitors the
+/// node.
+///
+/// With an `Idempotency-Key` header, repeating the request returns the original response instead
+/// of processing it again.
+#[post("/api/subscribe", data = "<data>")]
+async fn api_subscribe(
+    _rate_limit: RateLimit,
+    idempotency_key: IdempotencyKey,
+    content_type: Option<&ContentType>,
+    data: Data<'_>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<(Status, RawJson<String>)> {
+    use crate::schema::*;
+
+    let error = |status: Status, error: &str| {
+        let body = json!({ "error": error, "status": status.code });
+        Ok((status, RawJson(body.to_string())))
+    };
+    if !content_type.is_some_and(|ty| ty.is_json()) {
+        return error(Status::UnsupportedMediaType, "expected a JSON body");
+    }
+    let body = data.open(API_BODY_LIMIT).into_string().await?;
+    if !body.is_complete() {
+        return error(Status::PayloadTooLarge, "body is too large");
+    }
+    let body = body.into_inner();
+
+    let Some(key) = idempotency_key.0 else {
+        let (status, response) = subscribe(&ctx, &db, &body).await?;
+        return Ok((status, RawJson(response)));
+    };
+    if key.is_empty() || key.len() > IDEMPOTENCY_KEY_MAX_LEN {
+        let message = format!(
+            "Idempotency-Key must have between 1 and {} characters",
+            IDEMPOTENCY_KEY_MAX_LEN
+        );
+        return error(Status::BadRequest, &message);
+    }
+    match claim_idempotency_key(&db, key.clone(), &body).await? {
+        Claimed::New => {}
+        Claimed::Mismatch => {
+            return error(
+                Status::UnprocessableEntity,
+                "Idempotency-Key was already used for a different request",
+            );
+        }
+        Claimed::InProgress => {
+            return error(
+                Status::Conflict,
+                "a request with this Idempotency-Key is still being processed",
+            );
+        }
+        Claimed::Done(status, response) => {
+            log::info!(
+                "[{}] replaying the response for a known Idempotency-Key",
+                ctx.request_id()
+            );
+            return Ok((status, RawJson(response)));
+        }
+    }
+    match subscribe(&ctx, &db, &body).await {
+        Ok((status, response)) => {
+            let code = status.code as i16;
+            let stored = response.clone();
+            db.run(move |db| {
+                diesel::update(idempotency_keys::table.find(&key))
+                    .set((
+                        idempotency_keys::status.eq(code),
+                        idempotency_keys::response.eq(stored),
+                    ))
+                    .execute(db)
+            })
+            .await?;
+            Ok((status, RawJson(response)))
+        }
+        Err(e) => {
+            // Let the client try again
+            db.run(move |db| diesel::delete(idempotency_keys::table.find(&key)).execute(db))
+                .await?;
+            Err(e)
+        }
+    }
+}
+
+/// The actual work of `api_subscribe`, returning the status and the JSON response
+async fn subscribe(ctx: &Ctx<'_>, db: &DbConn, body: &str) -> Result<(Status, String)> {
+    let error = |status: Status, error: &str| {
+        let body = json!({ "error": error, "status": status.code });
+        Ok((status, body.to_string()))
+    };
+    let subscribe: Subscribe = match serde_json::from_str(body) {
+        Ok(subscribe) => subscribe,
+        Err(e) => return error(Status::BadRequest, &format!("malformed request: {}", e)),
+    };
+    let email = match subscribe.email.parse::<EmailAddress>() {
+        Ok(email) => email,
+        Err(errors) => {
+            let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+            let message = format!("invalid email address: {}", errors.join("; "));
+            return error(Status::BadRequest, &message);
+        }
+    };
+
+    let mut action = Action {
+        node: subscribe.node_id,
+        email,
+        op: Operation::Add,
+        backend: Backend::Email,
+        quiet_hours: None,
+        notify_mode: NotifyMode::Immediate,
+        lang: subscribe.lang,
+        weekly_summary: subscribe.weekly_summary,
+        force: subscribe.force,
+    };
+    match request_action(ctx, db, &mut action).await? {
+        Prepared::Blocked => error(Status::Forbidden, "this email address is blocked"),
+        Prepared::UnknownNode => {
+            let message = format!(
+                "node {} not found; set `force` to subscribe to it anyway",
+                action.node
+            );
+            error(Status::UnprocessableEntity, &message)
+        }
+        Prepared::InvalidPattern => error(
+            Status::BadRequest,
+            "a pattern must be a non-empty prefix followed by a single `*`",
+        ),
+        Prepared::Done { .. } => {
+            let body = json!({
+                "status": 202,
+                "message": "if needed, a confirmation email was sent",
+            });
+            Ok((Status::Accepted, body.to_string()))
+        }
+    }
+}
+
+#[derive(FromForm)]
+struct Resend {
+    email: EmailAddress,
+}
+
+/// Send the confirmation emails for all pending actions of an email address again.  The response
+/// does not tell whether there are any, so this cannot be used to find out who uses the service.
+#[post("/resend", data = "<resend>")]
+async fn resend(
+    _rate_limit: RateLimit,
+    resend: CsrfForm<Resend>,
+    ctx: Ctx<'_>,
+    db: DbConn,
+) -> Result<Template> {
+    let email = resend.into_inner().email;
+    // Like in `request_action`, the emails are only put into the outbox here and sent in the
+    // background, so that how long this takes does not tell whether there are any
+    let pending = Action::pending(&db, email.clone()).await?;
+    let confirmations = if pending.is_empty() || suppression::is_suppressed(&db, &email).await? {
+        vec![]
+    } else {
+        pending
+            .iter()
+            .map(|(action, node_name)| {
+                Ok(OutboxMessage::new(&confirmation_email(
+                    &ctx, action, node_name,
+                )?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
+    let queued = confirmations.len();
+    let cooldown = ctx.config().ui.get_resend_cooldown();
+    if Action::resend_pending(&db, email.clone(), cooldown, confirmations).await? && queued > 0 {
+        crate::outbox::wake(&ctx);
+        log::info!(
+            "[{}] queued {} confirmation emails again",
+            ctx.request_id(),
+            queued
+        );
+    }
+
+    let list_url = ctx.config().urls.absolute(uri!(list(email = &email)));
     Ok(ctx.template(
-        "prepare_action",
+        "resend",
         json!({
-            "action": action,
-            "node_name": node_name,
+            "email": email,
             "list_url": list_url,
         }),
     )?)
 }
 
+/// Confirming an action by just loading the link lets mail scanners and link previews confirm it
+/// without the user knowing.  Requests that say they are a prefetch, and with
+/// `confirm_interstitial` all requests, therefore only get a page with a button that POSTs back
+/// here.  Either way, running an action twice does no harm.
 #[get("/run_action?<signed_action>")]
-async fn run_action(signed_action: String, db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
+async fn run_action(
+    signed_action: String,
+    prefetch: Prefetch,
+    db: DbConn,
+    ctx: Ctx<'_>,
+) -> Result<Template> {
+    let config = ctx.config();
+    if !prefetch.0 && !config.ui.get_confirm_interstitial() {
+        return perform_action(&signed_action, &db, &ctx).await;
+    }
+    let action = match verify_action(&ctx, &signed_action) {
+        Ok(a) => a,
+        Err(_) => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+    let confirm_url = config
+        .urls
+        .absolute(uri!(run_action_post(signed_action = &signed_action)));
+    Ok(ctx.template(
+        "run_action_confirm",
+        json!({
+            "action": action,
+            "confirm_url": confirm_url,
+        }),
+    )?)
+}
+
+/// Confirmation via the button on the page above, and one-click unsubscription as triggered by
+/// mail clients via `List-Unsubscribe-Post`
+#[post("/run_action?<signed_action>")]
+async fn run_action_post(signed_action: String, db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
+    perform_action(&signed_action, &db, &ctx).await
+}
+
+fn verify_action(ctx: &Ctx<'_>, signed_action: &str) -> anyhow::Result<Action> {
+    SignedAction::decode(signed_action)
+        .and_then(|signed_action| ctx.config().secrets.signing_keys.verify(signed_action))
+}
+
+async fn perform_action(signed_action: &str, db: &DbConn, ctx: &Ctx<'_>) -> Result<Template> {
     // Determine and verify action
-    let action: Result<Action> = (|| {
-        let signed_action = BASE64_ENGINE.decode(signed_action.as_str())?;
-        let signed_action: SignedAction = deserialize_from_slice(signed_action.as_slice())?;
-        Ok(signed_action
-            .verify(&ctx.config().secrets.action_signing_key)
-            .map_err(|_| anyhow::anyhow!("signature verification failed"))?)
-    })();
-    let action = match action {
+    let action = match verify_action(ctx, signed_action) {
         Ok(a) => a,
         Err(_) => return Ok(ctx.template("run_action_error", json!({}))?),
     };
 
     // Execute action
-    let success = action.run(&db).await?;
+    let success = action.run(db).await?;
+    if success {
+        log::info!(
+            "[{}] {:?} monitor for node {}",
+            ctx.request_id(),
+            action.op,
+            action.node
+        );
+    }
 
     // Render
-    let list_url = ctx
-        .config()
-        .urls
-        .absolute(uri!(list(email = &action.email)));
+    let config = ctx.config();
+    let list_url = config.urls.absolute(uri!(list(email = &action.email)));
+    let api_token = email_token(config, Purpose::ListMonitors, action.email.clone());
+    let api_url = config.urls.absolute(uri!(api_monitors(
+        token = &api_token,
+        limit = _,
+        offset = _
+    )));
+    let export_token = email_token(config, Purpose::ExportData, action.email.clone());
+    let export_url = config.urls.absolute(uri!(export(token = &export_token)));
+    let delete_token = email_token(config, Purpose::DeleteData, action.email.clone());
+    let delete_url = config.urls.absolute(uri!(delete_me(token = &delete_token)));
     Ok(ctx.template(
         "run_action",
         json!({
             "action": action,
             "list_url": list_url,
+            "api_url": api_url,
+            "export_url": export_url,
+            "delete_url": delete_url,
             "success": success,
         }),
     )?)
 }
 
+#[get("/unsubscribe_all?<token>")]
+async fn unsubscribe_all(token: String, db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
+    perform_unsubscribe_all(&token, &db, &ctx).await
+}
+
+/// The one-click unsubscribe of RFC 8058, for emails that link `unsubscribe_all` in their
+/// `List-Unsubscribe` header
+#[post("/unsubscribe_all?<token>")]
+async fn unsubscribe_all_post(token: String, db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
+    perform_unsubscribe_all(&token, &db, &ctx).await
+}
+
+async fn perform_unsubscribe_all(token: &str, db: &DbConn, ctx: &Ctx<'_>) -> Result<Template> {
+    use crate::schema::*;
+
+    // Determine and verify email address
+    let email = match verify_email_token(ctx.config(), Purpose::UnsubscribeAll, token) {
+        Ok(e) => e,
+        Err(_) => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+
+    // Remove all monitors
+    let num_deleted = db
+        .run({
+            let email = email.clone();
+            move |db| {
+                diesel::delete(monitors::table.filter(monitors::email.eq(&*email))).execute(db)
+            }
+        })
+        .await?;
+
+    // Render
+    let list_url = ctx.config().urls.absolute(uri!(list(email = &email)));
+    Ok(ctx.template(
+        "unsubscribe_all",
+        json!({
+            "email": email,
+            "list_url": list_url,
+            "num_deleted": num_deleted,
+        }),
+    )?)
+}
+
+/// Deliver the emails waiting in the outbox; see `outbox::wake`
+#[post("/outbox")]
+async fn outbox_route(db: DbConn, ctx: Ctx<'_>) -> Result<()> {
+    ctx.mailer()?.retry_outbox(&db).await?;
+    Ok(())
+}
+
 #[get("/cron")]
 async fn cron_route(db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
     Ok(match ctx.update_nodes(&db).await? {
         cron::UpdateResult::NotEnoughOnline(online) => ctx.template(
             "cron_error",
             json!({
+                "already_running": false,
                 "not_enough_online": online,
             }),
         ),
+        cron::UpdateResult::AlreadyRunning => ctx.template(
+            "cron_error",
+            json!({
+                "already_running": true,
+            }),
+        ),
         cron::UpdateResult::AllOk => ctx.template("cron", json!({})),
     }?)
 }
 
+/// How many monitors `api_monitors` returns if the client does not say
+const API_DEFAULT_LIMIT: u32 = 100;
+/// The most monitors `api_monitors` returns at once
+const API_MAX_LIMIT: u32 = 1000;
+
+/// List the nodes monitored by the email address the token was issued for, as JSON.  The list is
+/// split into pages of at most `limit` monitors.
+#[get("/api/monitors?<token>&<limit>&<offset>")]
+async fn api_monitors(
+    token: String,
+    limit: Option<u32>,
+    offset: Option<u32>,
+    db: DbConn,
+    ctx: Ctx<'_>,
+) -> Result<(Status, RawJson<String>)> {
+    use crate::schema::*;
+
+    let email = match verify_email_token(ctx.config(), Purpose::ListMonitors, &token) {
+        Ok(e) => e,
+        Err(_) => {
+            let body = json!({ "error": "invalid or expired token", "status": 403 });
+            return Ok((Status::Forbidden, RawJson(body.to_string())));
+        }
+    };
+
+    let limit = limit.unwrap_or(API_DEFAULT_LIMIT).clamp(1, API_MAX_LIMIT);
+    let offset = offset.unwrap_or(0);
+    let (total, watched_nodes) = db
+        .run(move |db| {
+            let mine = monitors::table.filter(monitors::email.eq(&*email));
+            let total = mine.count().get_result::<i64>(db)?;
+            let watched_nodes = mine
+                .left_join(nodes::table.on(monitors::id.eq(nodes::id)))
+                .order_by(monitors::id)
+                .limit(limit.into())
+                .offset(offset.into())
+                .load::<MonitorNodeQuery>(db)?;
+            QueryResult::Ok((total, watched_nodes))
+        })
+        .await?;
+    let monitors: Vec<_> = watched_nodes
+        .into_iter()
+        .map(|watched| {
+            json!({
+                "id": watched.monitor.id,
+                "name": watched.node.as_ref().map(|node| &node.name),
+                "online": watched.node.as_ref().map(|node| node.online),
+                "created_at": watched.monitor.created_at.map(|created_at| {
+                    created_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+                }),
+            })
+        })
+        .collect();
+    let next_offset = offset.saturating_add(limit);
+    let next = (i64::from(next_offset) < total).then(|| {
+        ctx.config().urls.absolute(uri!(api_monitors(
+            token = &token,
+            limit = Some(limit),
+            offset = Some(next_offset)
+        )))
+    });
+    let body = json!({
+        "monitors": monitors,
+        "total": total,
+        "next_offset": next.as_ref().map(|_| next_offset),
+        "next": next,
+    });
+    Ok((Status::Ok, RawJson(body.to_string())))
+}
+
+/// Everything we store about the email address the token was issued for, as JSON.  We do not keep
+/// a record of the notifications we sent, so only those not delivered yet are included.
+#[get("/export?<token>")]
+async fn export(token: String, db: DbConn, ctx: Ctx<'_>) -> Result<(Status, RawJson<String>)> {
+    use crate::schema::*;
+
+    let email = match verify_email_token(ctx.config(), Purpose::ExportData, &token) {
+        Ok(e) => e,
+        Err(_) => {
+            let body = json!({ "error": "invalid or expired token", "status": 403 });
+            return Ok((Status::Forbidden, RawJson(body.to_string())));
+        }
+    };
+
+    let format = |at: DateTime<Utc>| at.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let body = db
+        .run::<_, anyhow::Result<_>>({
+            let email = email.clone();
+            move |db| {
+                let monitors = monitors::table
+                    .filter(monitors::email.eq(&*email))
+                    .order_by(monitors::id)
+                    .load::<MonitorQuery>(db)?;
+                let pending_actions = pending_actions::table
+                    .filter(pending_actions::email.eq(&*email))
+                    .order_by(pending_actions::sent_at)
+                    .select((
+                        pending_actions::node,
+                        pending_actions::node_name,
+                        pending_actions::sent_at,
+                    ))
+                    .load::<(String, String, DateTime<Utc>)>(db)?;
+                let queued = queued_notifications::table
+                    .filter(queued_notifications::email.eq(&*email))
+                    .order_by(queued_notifications::deliver_at)
+                    .select((
+                        queued_notifications::id,
+                        queued_notifications::online,
+                        queued_notifications::deliver_at,
+                    ))
+                    .load::<(String, bool, DateTime<Utc>)>(db)?;
+                let digest = digest_entries::table
+                    .filter(digest_entries::email.eq(&*email))
+                    .order_by(digest_entries::seq)
+                    .select((
+                        digest_entries::node_id,
+                        digest_entries::name,
+                        digest_entries::online,
+                        digest_entries::at,
+                        digest_entries::deliver_at,
+                    ))
+                    .load::<(String, String, bool, DateTime<Utc>, DateTime<Utc>)>(db)?;
+                // Recipients are stored as one comma-separated string
+                let outbox: Vec<DateTime<Utc>> = outbox::table
+                    .order_by(outbox::seq)
+                    .select((outbox::recipients, outbox::created_at))
+                    .load::<(String, DateTime<Utc>)>(db)?
+                    .into_iter()
+                    .filter(|(recipients, _)| recipients.split(',').any(|r| r == &*email))
+                    .map(|(_, created_at)| created_at)
+                    .collect();
+                let suppression = suppressions::table
+                    .find(&*email)
+                    .select((suppressions::reason, suppressions::created_at))
+                    .first::<(Option<String>, DateTime<Utc>)>(db)
+                    .optional()?;
+                let weekly_summary = weekly_summaries::table
+                    .find(&*email)
+                    .select(weekly_summaries::deliver_at)
+                    .first::<DateTime<Utc>>(db)
+                    .optional()?;
+                let monitors: Vec<_> = monitors
+                    .into_iter()
+                    .map(|monitor| {
+                        json!({
+                            "node_id": monitor.id,
+                            "backend": monitor.backend,
+                            "notify_mode": monitor.notify_mode,
+                            "weekly_summary": monitor.weekly_summary,
+                            "quiet_start": monitor.quiet_start,
+                            "quiet_end": monitor.quiet_end,
+                            "timezone": monitor.timezone,
+                            "lang": monitor.lang,
+                            "created_at": monitor.created_at.map(format),
+                        })
+                    })
+                    .collect();
+                let pending_actions: Vec<_> = pending_actions
+                    .into_iter()
+                    .map(|(node_id, node_name, sent_at)| {
+                        json!({
+                            "node_id": node_id,
+                            "node_name": node_name,
+                            "sent_at": format(sent_at),
+                        })
+                    })
+                    .collect();
+                let queued: Vec<_> = queued
+                    .into_iter()
+                    .map(|(node_id, online, deliver_at)| {
+                        json!({
+                            "node_id": node_id,
+                            "online": online,
+                            "deliver_at": format(deliver_at),
+                        })
+                    })
+                    .collect();
+                let digest: Vec<_> = digest
+                    .into_iter()
+                    .map(|(node_id, name, online, at, deliver_at)| {
+                        json!({
+                            "node_id": node_id,
+                            "name": name,
+                            "online": online,
+                            "at": format(at),
+                            "deliver_at": format(deliver_at),
+                        })
+                    })
+                    .collect();
+                let outbox: Vec<_> = outbox
+                    .into_iter()
+                    .map(|created_at| {
+                        json!({
+                            "created_at": format(created_at),
+                        })
+                    })
+                    .collect();
+                let suppression = suppression.map(|(reason, created_at)| {
+                    json!({
+                        "reason": reason,
+                        "created_at": format(created_at),
+                    })
+                });
+                let weekly_summary = weekly_summary.map(|deliver_at| {
+                    json!({
+                        "deliver_at": format(deliver_at),
+                    })
+                });
+                Ok(json!({
+                    "email": email,
+                    "exported_at": format(Utc::now()),
+                    "monitors": monitors,
+                    "pending_confirmations": pending_actions,
+                    "queued_notifications": queued,
+                    "digest_entries": digest,
+                    "undelivered_emails": outbox,
+                    "weekly_summary": weekly_summary,
+                    "suppression": suppression,
+                }))
+            }
+        })
+        .await?;
+    log::info!(
+        "[{}] exported the data of an email address",
+        ctx.request_id()
+    );
+    Ok((Status::Ok, RawJson(body.to_string())))
+}
+
+/// Ask whether to delete everything stored about the email address the token was issued for.
+/// Like `run_action`, this only shows a button, so that a mail scanner opening the link does not
+/// delete anything.
+#[get("/delete_me?<token>")]
+async fn delete_me(token: String, ctx: Ctx<'_>) -> Result<Template> {
+    let email = match verify_email_token(ctx.config(), Purpose::DeleteData, &token) {
+        Ok(e) => e,
+        Err(_) => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+    let confirm_url = ctx
+        .config()
+        .urls
+        .absolute(uri!(delete_me_post(token = &token)));
+    Ok(ctx.template(
+        "delete_me",
+        json!({
+            "email": email,
+            "confirm_url": confirm_url,
+        }),
+    )?)
+}
+
+/// Delete everything stored about the email address the token was issued for, and confirm that in
+/// an email.  Unlike `unsubscribe_all`, this also removes emails and notifications that were not
+/// delivered yet.  A suppression is kept, since it is what keeps us from sending to the address.
+#[post("/delete_me?<token>")]
+async fn delete_me_post(token: String, db: DbConn, ctx: Ctx<'_>) -> Result<Template> {
+    use crate::schema::*;
+
+    let email = match verify_email_token(ctx.config(), Purpose::DeleteData, &token) {
+        Ok(e) => e,
+        Err(_) => return Ok(ctx.template("run_action_error", json!({}))?),
+    };
+
+    let num_deleted = db
+        .run({
+            let email = email.clone();
+            move |db| {
+                db.transaction::<_, anyhow::Error, _>(|db| {
+                    let mut num_deleted = 0;
+                    num_deleted +=
+                        diesel::delete(monitors::table.filter(monitors::email.eq(&*email)))
+                            .execute(db)?;
+                    num_deleted += diesel::delete(
+                        pending_actions::table.filter(pending_actions::email.eq(&*email)),
+                    )
+                    .execute(db)?;
+                    num_deleted += diesel::delete(
+                        queued_notifications::table.filter(queued_notifications::email.eq(&*email)),
+                    )
+                    .execute(db)?;
+                    num_deleted += diesel::delete(
+                        digest_entries::table.filter(digest_entries::email.eq(&*email)),
+                    )
+                    .execute(db)?;
+                    num_deleted += diesel::delete(
+                        weekly_summaries::table.filter(weekly_summaries::email.eq(&*email)),
+                    )
+                    .execute(db)?;
+                    // Recipients are stored as one comma-separated string
+                    let outbox_entries: Vec<i32> = outbox::table
+                        .select((outbox::seq, outbox::recipients))
+                        .load::<(i32, String)>(db)?
+                        .into_iter()
+                        .filter(|(_, recipients)| recipients.split(',').any(|r| r == &*email))
+                        .map(|(seq, _)| seq)
+                        .collect();
+                    num_deleted +=
+                        diesel::delete(outbox::table.filter(outbox::seq.eq_any(outbox_entries)))
+                            .execute(db)?;
+                    Ok(num_deleted)
+                })
+            }
+        })
+        .await?;
+
+    // Running this again is fine, but there is nothing new to tell then
+    if num_deleted > 0 {
+        // Do not log the address; we just promised to forget it
+        log::info!(
+            "[{}] deleted all data of an email address ({} entries)",
+            ctx.request_id(),
+            num_deleted
+        );
+        if !suppression::is_suppressed(&db, &email).await? {
+            ctx.email("data_deleted", None, json!({ "email": email }), &email)
+                .await?;
+        }
+    }
+
+    Ok(ctx.template(
+        "delete_me",
+        json!({
+            "email": email,
+            "confirm_url": null,
+        }),
+    )?)
+}
+
+/// List all nodes with their current state and when they last changed it (as far as the history
+/// goes back), as JSON.  With `state`, only the nodes in that state are listed.  For the
+/// administrator, each node also says how many email addresses monitor it, directly or through a
+/// pattern; anyone else must not learn that.
+#[get("/api/nodes?<state>")]
+async fn api_nodes(
+    state: Option<String>,
+    admin: Option<Admin>,
+    db: DbConn,
+) -> Result<(Status, RawJson<String>)> {
+    use crate::schema::*;
+
+    let online = match state.as_deref() {
+        None => None,
+        Some("online") => Some(true),
+        Some("offline") => Some(false),
+        Some(_) => {
+            let body = json!({ "error": "`state` must be `online` or `offline`", "status": 400 });
+            return Ok((Status::BadRequest, RawJson(body.to_string())));
+        }
+    };
+    let with_subscribers = admin.is_some();
+
+    let (nodes, last_changes, monitors) = db
+        .run(move |db| {
+            let mut query = nodes::table.order_by(nodes::id).into_boxed();
+            if let Some(online) = online {
+                query = query.filter(nodes::online.eq(online));
+            }
+            let nodes = query.load::<NodeQuery>(db)?;
+            let last_changes = node_history::table
+                .group_by(node_history::node_id)
+                .select((node_history::node_id, diesel::dsl::max(node_history::at)))
+                .load::<(String, Option<DateTime<Utc>>)>(db)?;
+            let monitors = if with_subscribers {
+                monitors::table
+                    .select((monitors::id, monitors::email))
+                    .load::<(String, String)>(db)?
+            } else {
+                Vec::new()
+            };
+            Ok::<_, diesel::result::Error>((nodes, last_changes, monitors))
+        })
+        .await?;
+    let last_changes: HashMap<String, DateTime<Utc>> = last_changes
+        .into_iter()
+        .filter_map(|(id, at)| Some((id, at?)))
+        .collect();
+    // Group the monitors once instead of going through all of them for every node
+    let mut direct: HashMap<&str, HashSet<&str>> = HashMap::new();
+    let mut patterns: Vec<(&str, &str)> = Vec::new();
+    for (id, email) in &monitors {
+        match node_prefix(id) {
+            Some(prefix) => patterns.push((prefix, email)),
+            None => {
+                direct.entry(id).or_default().insert(email);
+            }
+        }
+    }
+    let nodes: Vec<_> = nodes
+        .into_iter()
+        .map(|node| {
+            let mut entry = json!({
+                "id": node.id,
+                "name": node.name,
+                "state": NodeState::from_online(node.online),
+                "last_change": last_changes
+                    .get(&node.id)
+                    .map(|at| at.to_rfc3339_opts(SecondsFormat::Micros, true)),
+            });
+            if with_subscribers {
+                // Like for notifications, an address that monitors a node in several ways counts
+                // once
+                let mut subscribers = direct.get(node.id.as_str()).cloned().unwrap_or_default();
+                subscribers.extend(
+                    patterns
+                        .iter()
+                        .filter(|(prefix, _)| node.id.starts_with(prefix))
+                        .map(|&(_, email)| email),
+                );
+                entry["subscribers"] = subscribers.len().into();
+            }
+            entry
+        })
+        .collect();
+    let body = json!({ "nodes": nodes });
+    Ok((Status::Ok, RawJson(body.to_string())))
+}
+
+/// The most events `node_history` returns at once
+const HISTORY_LIMIT: i64 = 500;
+
+/// List when the given node went online or offline, oldest first, as JSON.  Only events after
+/// `since` (an RFC 3339 timestamp) are included; if there are more than fit into one response,
+/// `next` is the URL for the rest.
+#[get("/node/<id>/history?<since>")]
+async fn node_history(
+    id: String,
+    since: Option<String>,
+    db: DbConn,
+    ctx: Ctx<'_>,
+) -> Result<(Status, RawJson<String>)> {
+    use crate::schema::*;
+
+    let since = match since
+        .as_deref()
+        .map(DateTime::parse_from_rfc3339)
+        .transpose()
+    {
+        Ok(since) => since.map(|since| since.with_timezone(&Utc)),
+        Err(_) => {
+            let body =
+                json!({ "error": "`since` is not a valid RFC 3339 timestamp", "status": 400 });
+            return Ok((Status::BadRequest, RawJson(body.to_string())));
+        }
+    };
+
+    let mut events = db
+        .run({
+            let id = id.clone();
+            move |db| {
+                let mut query = node_history::table
+                    .filter(node_history::node_id.eq(id))
+                    .select((node_history::at, node_history::online))
+                    .order_by(node_history::at)
+                    .limit(HISTORY_LIMIT + 1)
+                    .into_boxed();
+                if let Some(since) = since {
+                    query = query.filter(node_history::at.gt(since));
+                }
+                query.load::<(DateTime<Utc>, bool)>(db)
+            }
+        })
+        .await?;
+    let more = events.len() as i64 > HISTORY_LIMIT;
+    events.truncate(HISTORY_LIMIT as usize);
+    let format = |at: DateTime<Utc>| at.to_rfc3339_opts(SecondsFormat::Micros, true);
+    let next = match events.last() {
+        Some(&(last, _)) if more => Some(
+            ctx.config()
+                .urls
+                .absolute(uri!(node_history(id = &id, since = Some(format(last))))),
+        ),
+        _ => None,
+    };
+    let events: Vec<_> = events
+        .into_iter()
+        .map(|(at, online)| {
+            json!({
+                "timestamp": format(at),
+                "state": if online { "online" } else { "offline" },
+            })
+        })
+        .collect();
+    let body = json!({
+        "events": events,
+        "next": next,
+    });
+    Ok((Status::Ok, RawJson(body.to_string())))
+}
+
+/// Check that the database and the SMTP host are reachable
+#[get("/health")]
+async fn health(db: Option<DbConn>, ctx: Ctx<'_>) -> (Status, RawJson<String>) {
+    let db_ok = match db {
+        Some(db) => db
+            .run(|db| diesel::sql_query("SELECT 1").execute(db))
+            .await
+            .is_ok(),
+        None => false,
+    };
+    let smtp_ok = match ctx.mailer() {
+        Ok(mailer) => mailer.test_connection().await.unwrap_or(false),
+        Err(_) => false,
+    };
+
+    let component_status = |ok: bool| if ok { "ok" } else { "error" };
+    let status = if db_ok && smtp_ok {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    let body = json!({
+        "db": component_status(db_ok),
+        "smtp": component_status(smtp_ok),
+    });
+    (status, RawJson(body.to_string()))
+}
+
+/// Send a test email to `to`, to check that emails can be sent with the current configuration
+#[post("/admin/test_email?<to>")]
+async fn admin_test_email(
+    _admin: Admin,
+    to: EmailAddress,
+    ctx: Ctx<'_>,
+) -> (Status, RawJson<String>) {
+    let (status, body) = match ctx.email("test_email", None, json!({}), &to).await {
+        Ok(()) => {
+            log::info!("[{}] sent test email to {}", ctx.request_id(), to);
+            (Status::Ok, json!({ "ok": true }))
+        }
+        Err(e) => {
+            log::error!(
+                "[{}] failed to send test email to {}: {:#}",
+                ctx.request_id(),
+                to,
+                e
+            );
+            let error = format!("{:#}", e);
+            (Status::BadGateway, json!({ "ok": false, "error": error }))
+        }
+    };
+    (status, RawJson(body.to_string()))
+}
+
+/// List the suppressed addresses
+#[get("/admin/suppressions")]
+async fn admin_suppressions(_admin: Admin, db: DbConn) -> Result<RawJson<String>> {
+    use crate::schema::*;
+
+    let suppressions = db
+        .run(|db| {
+            suppressions::table
+                .order_by(suppressions::email)
+                .load::<SuppressionQuery>(db)
+        })
+        .await?;
+    let body = json!({ "suppressions": suppressions });
+    Ok(RawJson(body.to_string()))
+}
+
+/// How many addresses monitor each node, for the community to see which nodes people care about.
+/// Only the counts are returned, never who monitors a node.
+#[get("/admin/subscribers")]
+async fn admin_subscribers(_admin: Admin, db: DbConn) -> Result<RawJson<String>> {
+    use crate::schema::*;
+    use diesel::dsl::count_star;
+
+    let counts = db
+        .run(|db| {
+            monitors::table
+                .group_by(monitors::id)
+                .select((monitors::id, count_star()))
+                .order_by(monitors::id)
+                .load::<(String, i64)>(db)
+        })
+        .await?;
+    let body: serde_json::Map<String, serde_json::Value> = counts
+        .into_iter()
+        .map(|(node, count)| (node, count.into()))
+        .collect();
+    Ok(RawJson(serde_json::Value::Object(body).to_string()))
+}
+
+/// The templates `admin_preview` can render
+const PREVIEW_TEMPLATES: &[&str] = &[
+    "notification",
+    "node_down",
+    "node_up",
+    "notifications",
+    "notification_matrix",
+];
+
+#[derive(FromForm)]
+struct Preview {
+    node_id: String,
+    lang: Option<String>,
+    /// `up` or `down`
+    direction: Option<String>,
+    html: bool,
+}
+
+/// Render a notification template about the node with ID `node_id` like a real notification
+/// would be rendered, without sending anything.  The event is about the node reaching its current
+/// state, unless `direction` says otherwise.  Emails are shown as the rendered text; with `html`,
+/// only their HTML part is shown.
+#[get("/admin/preview/<template>?<preview..>")]
+async fn admin_preview(
+    _admin: Admin,
+    template: &str,
+    preview: Preview,
+    db: DbConn,
+    ctx: Ctx<'_>,
+) -> Result<(Status, (ContentType, String))> {
+    use crate::notify::{Direction, Watcher};
+    use crate::schema::*;
+
+    let Preview {
+        node_id,
+        lang,
+        direction,
+        html,
+    } = preview;
+    let error = |status, msg: String| Ok((status, (ContentType::Plain, msg)));
+    if !PREVIEW_TEMPLATES.contains(&template) {
+        return error(
+            Status::NotFound,
+            format!(
+                "cannot preview template `{}`; available are: {}",
+                template,
+                PREVIEW_TEMPLATES.join(", ")
+            ),
+        );
+    }
+    let direction = match direction.as_deref() {
+        None => None,
+        Some("up") => Some(Direction::Up),
+        Some("down") => Some(Direction::Down),
+        Some(_) => {
+            return error(
+                Status::BadRequest,
+                "`direction` must be `up` or `down`".to_owned(),
+            )
+        }
+    };
+    let node = db
+        .run({
+            let node_id = node_id.clone();
+            move |db| nodes::table.find(node_id).first::<NodeQuery>(db).optional()
+        })
+        .await?;
+    let Some(node) = node else {
+        return error(Status::NotFound, format!("node {} not found", node_id));
+    };
+    let direction = direction.or(match template {
+        "node_up" => Some(Direction::Up),
+        "node_down" => Some(Direction::Down),
+        _ => None,
+    });
+    let event = ctx.event_for(node, direction);
+    if template == "notification_matrix" {
+        return Ok((Status::Ok, (ContentType::Plain, ctx.matrix_text(&event)?)));
+    }
+
+    let watcher = Watcher {
+        email: ctx.config().ui.email_from.to_string(),
+        lang: lang.clone(),
+        pattern: None,
+    };
+    let (vals, template) = match template {
+        "notifications" => (
+            ctx.combined_notification_vals(&[&event], &watcher)?.0,
+            template,
+        ),
+        "notification" => (ctx.notification_vals(&event, &watcher)?.0, template),
+        // Like real notifications, fall back to the generic template
+        _ => (
+            ctx.notification_vals(&event, &watcher)?.0,
+            ctx.notification_template(&event, lang.as_deref()),
+        ),
+    };
+    let (_, text) = ctx.render_email(template, lang.as_deref(), vals)?;
+    if !html {
+        return Ok((Status::Ok, (ContentType::Plain, text)));
+    }
+    match email::html_part(&text) {
+        Some(html) => Ok((Status::Ok, (ContentType::HTML, html.to_owned()))),
+        None => error(
+            Status::NotFound,
+            format!("template `{}` has no HTML part", template),
+        ),
+    }
+}
+
+/// Stop sending emails to `email`, e.g. because it bounced or complained
+#[post("/admin/suppressions?<email>&<reason>")]
+async fn admin_suppress(
+    _admin: Admin,
+    email: EmailAddress,
+    reason: Option<String>,
+    db: DbConn,
+    ctx: Ctx<'_>,
+) -> Result<RawJson<String>> {
+    use crate::schema::*;
+
+    log::info!("[{}] suppressing emails to {}", ctx.request_id(), email);
+    db.run(move |db| {
+        let suppression = Suppression {
+            email: &email,
+            reason: reason.as_deref(),
+            created_at: chrono::Utc::now(),
+        };
+        diesel::insert_into(suppressions::table)
+            .values(&suppression)
+            .on_conflict(suppressions::email)
+            .do_update()
+            .set(&suppression)
+            .execute(db)
+    })
+    .await?;
+    Ok(RawJson(json!({ "ok": true }).to_string()))
+}
+
+/// Allow sending emails to `email` again
+#[delete("/admin/suppressions?<email>")]
+async fn admin_unsuppress(
+    _admin: Admin,
+    email: EmailAddress,
+    db: DbConn,
+    ctx: Ctx<'_>,
+) -> Result<RawJson<String>> {
+    use crate::schema::*;
+
+    log::info!(
+        "[{}] no longer suppressing emails to {}",
+        ctx.request_id(),
+        email
+    );
+    let removed = db
+        .run(move |db| diesel::delete(suppressions::table.find(&*email)).execute(db))
+        .await?;
+    Ok(RawJson(
+        json!({ "ok": true, "removed": removed > 0 }).to_string(),
+    ))
+}
+
+/// Whether the service is in maintenance mode
+#[get("/admin/maintenance")]
+fn admin_maintenance(_admin: Admin, maintenance: &State<Maintenance>) -> RawJson<String> {
+    RawJson(json!({ "maintenance_mode": maintenance.is_on() }).to_string())
+}
+
+/// Switch maintenance mode on or off.  This only lasts until the next restart; to start in
+/// maintenance mode, set `maintenance_mode`.
+#[post("/admin/maintenance?<on>")]
+fn admin_set_maintenance(
+    _admin: Admin,
+    on: bool,
+    maintenance: &State<Maintenance>,
+    ctx: Ctx<'_>,
+) -> RawJson<String> {
+    log::info!(
+        "[{}] switching maintenance mode {}",
+        ctx.request_id(),
+        if on { "on" } else { "off" }
+    );
+    maintenance.set(on);
+    RawJson(json!({ "maintenance_mode": on }).to_string())
+}
+
+/// The response to requests that maintenance mode keeps from their route: the `maintenance` page,
+/// or JSON for clients that prefer it, with status 503
+struct Unavailable {
+    page: Option<Template>,
+    retry_after: Duration,
+}
+
+impl<'r> response::Responder<'r, 'static> for Unavailable {
+    fn respond_to(self, r: &'r Request<'_>) -> response::Result<'static> {
+        let status = Status::ServiceUnavailable;
+        let mut response = match self.page {
+            Some(page) => page.respond_to(r)?,
+            None => {
+                let body = json!({ "error": "maintenance", "status": status.code });
+                RawJson(body.to_string()).respond_to(r)?
+            }
+        };
+        response.set_status(status);
+        response.set_raw_header("Retry-After", self.retry_after.as_secs().to_string());
+        Ok(response)
+    }
+}
+
+/// Where `MaintenanceMode` sends all requests it keeps from their route (the path must be
+/// `maintenance::MAINTENANCE_PATH`).  Outside of maintenance, this page does not exist.
+#[get("/maintenance")]
+fn maintenance(
+    wants_json: WantsJson,
+    maintenance: &State<Maintenance>,
+    ctx: Ctx<'_>,
+) -> Result<Option<Unavailable>> {
+    if !maintenance.is_on() {
+        return Ok(None);
+    }
+    let page = if wants_json.0 {
+        None
+    } else {
+        Some(ctx.template("maintenance", json!({}))?)
+    };
+    Ok(Some(Unavailable {
+        page,
+        retry_after: ctx.config().ui.get_maintenance_retry_after(),
+    }))
+}
+
+/// Metrics in the Prometheus text format
+#[get("/metrics")]
+fn metrics(ctx: Ctx<'_>) -> String {
+    ctx.metrics().render()
+}
+
+/// Whether to answer `request` with JSON instead of a page: always for the API (also when
+/// maintenance mode diverted the request), otherwise if the client prefers it
+fn wants_json(request: &Request<'_>) -> bool {
+    let diverted = request.local_cache(|| DivertedFrom(None));
+    let path = match &diverted.0 {
+        Some(path) => path.as_str(),
+        None => request.uri().path().as_str(),
+    };
+    path.starts_with("/api/")
+        || request
+            .accept()
+            .is_some_and(|accept| accept.preferred().is_json())
+}
+
+/// `wants_json` as a request guard
+struct WantsJson(bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WantsJson {
+    type Error = std::convert::Infallible;
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(WantsJson(wants_json(request)))
+    }
+}
+
+/// Renders errors as JSON for the API and for clients that prefer it, and as Rocket's default HTML
+/// page otherwise.  The body only names the status, so nothing about the cause of the error leaks
+/// to the client; that goes to the log.
+#[derive(Clone)]
+struct ErrorCatcher;
+
+#[rocket::async_trait]
+impl catcher::Handler for ErrorCatcher {
+    async fn handle<'r>(&self, status: Status, request: &'r Request<'_>) -> catcher::Result<'r> {
+        if !wants_json(request) {
+            return Catcher::default().handler.handle(status, request).await;
+        }
+        let body = json!({
+            "error": status.reason_lossy().to_ascii_lowercase(),
+            "status": status.code,
+        });
+        (status, RawJson(body.to_string())).respond_to(request)
+    }
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    vec![Catcher::new(None, ErrorCatcher)]
+}
+
 pub fn routes() -> Vec<rocket::Route> {
     routes![
         index,
         list,
         list_formfail,
         prepare_action,
+        resend,
         run_action,
-        cron_route
+        run_action_post,
+        unsubscribe_all,
+        unsubscribe_all_post,
+        api_monitors,
+        api_subscribe,
+        export,
+        delete_me,
+        delete_me_post,
+        api_nodes,
+        node_history,
+        cron_route,
+        outbox_route,
+        health,
+        metrics,
+        maintenance,
+        admin_test_email,
+        admin_subscribers,
+        admin_preview,
+        admin_maintenance,
+        admin_set_maintenance,
+        admin_suppressions,
+        admin_suppress,
+        admin_unsuppress
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use rocket::figment::Figment;
+    use rocket::http::{ContentType, Header};
+    use rocket::local::asynchronous::Client;
+    use rocket::tokio::sync::Mutex;
+
+    use super::*;
+
+    /// Migrating the database from several tests at once does not work
+    static IGNITE: Mutex<()> = Mutex::const_new(());
+
+    /// The `admin_token` configured for the instances that `client` starts
+    const ADMIN_TOKEN: &str = "admin-token-for-the-test-instance";
+
+    /// A client for an instance that uses the database at `FFMON_TEST_DATABASE_URL`, or `None`
+    /// if that is not set; tests needing a database are skipped then.  The database is never
+    /// emptied, so tests only look at rows they created themselves.
+    async fn client() -> Option<Client> {
+        let Ok(database) = std::env::var("FFMON_TEST_DATABASE_URL") else {
+            eprintln!("FFMON_TEST_DATABASE_URL is not set, skipping test");
+            return None;
+        };
+        let figment = Figment::from(rocket::Config::debug_default())
+            .merge(("databases.postgres.url", database))
+            .merge((
+                crate::CONFIG_SECTION,
+                json!({
+                    "ui": {
+                        "instance_name": "Freifunk Test",
+                        "instance_article_dative": "der",
+                        "email_from": "monitor@example.org",
+                    },
+                    "urls": {
+                        "root": "http://127.0.0.1:8000/",
+                        "nodes": "http://127.0.0.1:8000/nodes.json",
+                        "sources": "https://github.com/freifunk-saar/ff-node-monitor",
+                    },
+                    "secrets": {
+                        "action_signing_key": "00".repeat(32),
+                        "mail_transport": "stub",
+                        "admin_token": ADMIN_TOKEN,
+                    },
+                }),
+            ));
+        let _guard = IGNITE.lock().await;
+        Some(Client::tracked(crate::server(figment)).await.unwrap())
+    }
+
+    #[get("/api/fail")]
+    fn fail() -> Result<()> {
+        Err(anyhow::anyhow!("internal details").into())
+    }
+
+    #[rocket::async_test]
+    async fn api_errors_are_json() {
+        let rocket = rocket::custom(rocket::Config::debug_default())
+            .mount("/", routes![fail])
+            .register("/", catchers());
+        let client = Client::tracked(rocket).await.unwrap();
+        for (uri, status) in [
+            ("/api/fail", Status::InternalServerError),
+            ("/api/nothing-here", Status::NotFound),
+        ] {
+            let response = client.get(uri).dispatch().await;
+            assert_eq!(response.status(), status);
+            assert_eq!(response.content_type(), Some(ContentType::JSON));
+            let body = response.into_string().await.unwrap();
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["status"], status.code);
+        }
+        // Elsewhere, only for clients asking for it
+        let response = client.get("/nothing-here").dispatch().await;
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+    }
+
+    /// A name no earlier test run used
+    fn unique(prefix: &str) -> String {
+        format!("{}-{:x}", prefix, Utc::now().timestamp_micros())
+    }
+
+    /// The body of `response`, with the CSRF token (which contains its expiry time) left out
+    async fn body_without_csrf(response: rocket::local::asynchronous::LocalResponse<'_>) -> String {
+        let body = response.into_string().await.unwrap();
+        let marker = "name=\"csrf\" value=\"";
+        let start = body.find(marker).unwrap() + marker.len();
+        let end = start + body[start..].find('"').unwrap();
+        format!("{}{}", &body[..start], &body[end..])
+    }
+
+    /// An action adding a monitor that does not exist yet
+    fn new_action() -> Action {
+        Action {
+            node: unique("node"),
+            email: EmailAddress::new(format!("{}@example.org", unique("watcher"))).unwrap(),
+            op: Operation::Add,
+            backend: Backend::Email,
+            quiet_hours: None,
+            notify_mode: NotifyMode::Immediate,
+            lang: None,
+            weekly_summary: false,
+            force: false,
+        }
+    }
+
+    async fn is_monitored(db: &DbConn, action: &Action) -> bool {
+        use crate::schema::*;
+
+        let (node, email) = (action.node.clone(), action.email.clone());
+        db.run(move |db| {
+            monitors::table
+                .find((node.as_str(), &*email))
+                .first::<MonitorQuery>(db)
+                .optional()
+        })
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    #[rocket::async_test]
+    async fn adding_again_updates_settings() {
+        use crate::schema::*;
+
+        let Some(client) = client().await else {
+            return;
+        };
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let mut action = new_action();
+        assert!(action.run(&db).await.unwrap());
+        assert!(!action.run(&db).await.unwrap());
+        action.weekly_summary = true;
+        assert!(action.run(&db).await.unwrap());
+        action.notify_mode = NotifyMode::Digest;
+        action.lang = Some("en".to_owned());
+        assert!(action.run(&db).await.unwrap());
+        assert!(!action.run(&db).await.unwrap());
+        let (node, email) = (action.node.clone(), action.email.clone());
+        let monitor = db
+            .run(move |db| {
+                monitors::table
+                    .find((node.as_str(), &*email))
+                    .first::<MonitorQuery>(db)
+            })
+            .await
+            .unwrap();
+        assert!(monitor.weekly_summary);
+        assert_eq!(monitor.notify_mode, NotifyMode::Digest.as_str());
+        assert_eq!(monitor.lang.as_deref(), Some("en"));
+        assert!(action.has_settings_of(&monitor));
+    }
+
+    #[rocket::async_test]
+    async fn prepare_action_notices_changed_settings() {
+        use crate::schema::*;
+
+        let Some(client) = client().await else {
+            return;
+        };
+        let config = client.rocket().state::<Config>().unwrap();
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let action = new_action();
+        assert!(action.run(&db).await.unwrap());
+        let email = action.email.to_string();
+        for notify_mode in ["immediate", "digest"] {
+            let form = format!(
+                "node={}&email={}&op=add&notify_mode={}&force=true&csrf={}",
+                action.node,
+                email,
+                notify_mode,
+                csrf::token(config, None)
+            );
+            let response = client
+                .post(uri!(prepare_action))
+                .header(ContentType::Form)
+                .body(&form)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+        // Only changing the notification mode needs confirming
+        let email2 = email.clone();
+        let queued: i64 = db
+            .run(move |db| {
+                outbox::table
+                    .filter(outbox::recipients.eq(email2))
+                    .count()
+                    .get_result(db)
+            })
+            .await
+            .unwrap();
+        assert_eq!(queued, 1);
+    }
+
+    #[rocket::async_test]
+    async fn prefetch_does_not_run_action() {
+        let Some(client) = client().await else {
+            return;
+        };
+        let config = client.rocket().state::<Config>().unwrap();
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let action = new_action();
+        let signed_action = config
+            .secrets
+            .signing_keys
+            .sign(action.clone(), config.ui.get_token_validity())
+            .encode();
+        let uri = uri!(run_action(signed_action = &signed_action));
+
+        for header in ["Sec-Purpose", "Purpose"] {
+            let response = client
+                .get(uri.clone())
+                .header(Header::new(header, "prefetch"))
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            let body = response.into_string().await.unwrap();
+            assert!(body.contains("method=\"post\""), "{}", body);
+            assert!(
+                !is_monitored(&db, &action).await,
+                "{} ran the action",
+                header
+            );
+        }
+
+        let response = client.post(uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(is_monitored(&db, &action).await);
+    }
+
+    #[rocket::async_test]
+    async fn prepare_action_does_not_tell_whether_address_monitors_node() {
+        use crate::schema::*;
+
+        let Some(client) = client().await else {
+            return;
+        };
+        let config = client.rocket().state::<Config>().unwrap();
+        let action = new_action();
+        let email = action.email.to_string();
+        let form = format!(
+            "node={}&email={}&op=add&force=true&csrf={}",
+            action.node,
+            email,
+            csrf::token(config, None)
+        );
+        let prepare = || async {
+            let response = client
+                .post(uri!(prepare_action))
+                .header(ContentType::Form)
+                .body(&form)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+            body_without_csrf(response).await
+        };
+
+        let not_monitored = prepare().await;
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        assert!(action.run(&db).await.unwrap());
+        let monitored = prepare().await;
+        assert_eq!(not_monitored, monitored);
+
+        // Only the first request asked for confirmation
+        let queued: i64 = db
+            .run(move |db| {
+                outbox::table
+                    .filter(outbox::recipients.eq(email))
+                    .count()
+                    .get_result(db)
+            })
+            .await
+            .unwrap();
+        assert_eq!(queued, 1);
+    }
+
+    /// The `subscribers` field of `node` in the response to `request` for `api_nodes`
+    async fn subscribers(
+        request: rocket::local::asynchronous::LocalRequest<'_>,
+        node: &str,
+    ) -> Option<serde_json::Value> {
+        let response = request.dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().await.unwrap();
+        let mut body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let entry = body["nodes"]
+            .as_array_mut()
+            .unwrap()
+            .iter_mut()
+            .find(|entry| entry["id"] == node)
+            .unwrap();
+        entry.get_mut("subscribers").map(serde_json::Value::take)
+    }
+
+    #[rocket::async_test]
+    async fn only_admin_sees_subscribers() {
+        use crate::schema::*;
+
+        let Some(client) = client().await else {
+            return;
+        };
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let direct = new_action();
+        let node = direct.node.clone();
+        db.run({
+            let node = node.clone();
+            move |db| {
+                diesel::insert_into(nodes::table)
+                    .values((
+                        nodes::id.eq(node),
+                        nodes::name.eq("Gutenberg"),
+                        nodes::online.eq(true),
+                    ))
+                    .execute(db)
+            }
+        })
+        .await
+        .unwrap();
+        assert!(direct.run(&db).await.unwrap());
+        // The same address through a pattern counts once, another one counts again
+        let mut pattern = direct.clone();
+        pattern.node = format!("{}*", node);
+        assert!(pattern.run(&db).await.unwrap());
+        let mut other = new_action();
+        other.node = pattern.node.clone();
+        assert!(other.run(&db).await.unwrap());
+
+        assert_eq!(subscribers(client.get("/api/nodes"), &node).await, None);
+        let wrong_token = client
+            .get("/api/nodes")
+            .header(Header::new("Authorization", "Bearer wrong"));
+        assert_eq!(subscribers(wrong_token, &node).await, None);
+        let admin = client.get("/api/nodes").header(Header::new(
+            "Authorization",
+            format!("Bearer {}", ADMIN_TOKEN),
+        ));
+        assert_eq!(subscribers(admin, &node).await, Some(2.into()));
+    }
+
+    #[rocket::async_test]
+    async fn background_jobs_run_during_maintenance() {
+        let Some(client) = client().await else {
+            return;
+        };
+        client.rocket().state::<Maintenance>().unwrap().set(true);
+        let response = client.get(uri!(cron_route)).dispatch().await;
+        assert_ne!(response.status(), Status::ServiceUnavailable);
+        let response = client.post(uri!(outbox_route)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/").dispatch().await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+    }
+
+    #[rocket::async_test]
+    async fn resend_queues_confirmations() {
+        use crate::schema::*;
+
+        let Some(client) = client().await else {
+            return;
+        };
+        let config = client.rocket().state::<Config>().unwrap();
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let action = new_action();
+        let email = action.email.to_string();
+        // Pretend the first confirmation email went out long ago
+        action
+            .remember_pending(&db, "Gutenberg".to_owned(), None)
+            .await
+            .unwrap();
+        let (node, address) = (action.node.clone(), email.clone());
+        db.run(move |db| {
+            diesel::update(pending_actions::table.find((address, node)))
+                .set(pending_actions::sent_at.eq(Utc::now() - chrono::Duration::days(1)))
+                .execute(db)
+        })
+        .await
+        .unwrap();
+
+        let form = format!("email={}&csrf={}", email, csrf::token(config, None));
+        for _ in 0..2 {
+            let response = client
+                .post(uri!(resend))
+                .header(ContentType::Form)
+                .body(&form)
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+        // The second request came within the cooldown
+        let queued: i64 = db
+            .run(move |db| {
+                outbox::table
+                    .filter(outbox::recipients.eq(email))
+                    .count()
+                    .get_result(db)
+            })
+            .await
+            .unwrap();
+        assert_eq!(queued, 1);
+    }
+
+    /// Subscribe to `node` through the API with the given `Idempotency-Key`, and return the status
+    /// and the body of the response
+    async fn subscribe_with_key(
+        client: &Client,
+        key: &str,
+        email: &str,
+        node: &str,
+    ) -> (Status, String) {
+        let body = json!({ "email": email, "node_id": node, "force": true });
+        let response = client
+            .post(uri!(api_subscribe))
+            .header(ContentType::JSON)
+            .header(Header::new("Idempotency-Key", key.to_owned()))
+            .body(body.to_string())
+            .dispatch()
+            .await;
+        (response.status(), response.into_string().await.unwrap())
+    }
+
+    /// Pretend that a request with `key` is being processed since `started`
+    async fn claim_key(db: &DbConn, key: &str, email: &str, node: &str, started: DateTime<Utc>) {
+        use crate::schema::*;
+
+        let request = json!({ "email": email, "node_id": node, "force": true }).to_string();
+        let request_hash = request_hash(&request);
+        let key = key.to_owned();
+        db.run(move |db| {
+            diesel::insert_into(idempotency_keys::table)
+                .values(&IdempotentRequest {
+                    key: &key,
+                    request_hash: &request_hash,
+                    status: None,
+                    response: None,
+                    created_at: started,
+                })
+                .execute(db)
+        })
+        .await
+        .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn idempotency_key_replays_response() {
+        use crate::schema::*;
+
+        let Some(client) = client().await else {
+            return;
+        };
+        let action = new_action();
+        let (key, email) = (unique("key"), action.email.to_string());
+        let first = subscribe_with_key(&client, &key, &email, &action.node).await;
+        assert_eq!(first.0, Status::Accepted);
+        let again = subscribe_with_key(&client, &key, &email, &action.node).await;
+        assert_eq!(again, first);
+        // The request was only processed once
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let queued: i64 = db
+            .run(move |db| {
+                outbox::table
+                    .filter(outbox::recipients.eq(email))
+                    .count()
+                    .get_result(db)
+            })
+            .await
+            .unwrap();
+        assert_eq!(queued, 1);
+
+        // Another request must not use the same key
+        let (status, _) =
+            subscribe_with_key(&client, &key, "other@example.org", &action.node).await;
+        assert_eq!(status, Status::UnprocessableEntity);
+    }
+
+    #[rocket::async_test]
+    async fn idempotency_key_in_progress() {
+        let Some(client) = client().await else {
+            return;
+        };
+        let db = DbConn::get_one(client.rocket()).await.unwrap();
+        let action = new_action();
+        let email = action.email.to_string();
+
+        let key = unique("running");
+        claim_key(&db, &key, &email, &action.node, Utc::now()).await;
+        let (status, _) = subscribe_with_key(&client, &key, &email, &action.node).await;
+        assert_eq!(status, Status::Conflict);
+
+        // A request that was given up on does not block retries
+        let key = unique("abandoned");
+        let started = Utc::now() - chrono::Duration::from_std(IDEMPOTENCY_CLAIM_TIMEOUT).unwrap();
+        claim_key(&db, &key, &email, &action.node, started).await;
+        let (status, _) = subscribe_with_key(&client, &key, &email, &action.node).await;
+        assert_eq!(status, Status::Accepted);
+        let (status, _) = subscribe_with_key(&client, &key, &email, &action.node).await;
+        assert_eq!(status, Status::Accepted);
+    }
+}
@@ -4,6 +4,14 @@ diesel::table! {
     monitors (id, email) {
         id -> Varchar,
         email -> Varchar,
+        backend -> Varchar,
+        quiet_start -> Nullable<Time>,
+        quiet_end -> Nullable<Time>,
+        timezone -> Nullable<Varchar>,
+        notify_mode -> Varchar,
+        lang -> Nullable<Varchar>,
+        created_at -> Nullable<Timestamptz>,
+        weekly_summary -> Bool,
     }
 }
 
@@ -12,7 +20,106 @@ diesel::table! {
         id -> Varchar,
         name -> Varchar,
         online -> Bool,
+        pending_since -> Nullable<Timestamptz>,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(monitors, nodes,);
+diesel::table! {
+    queued_notifications (id, email) {
+        id -> Varchar,
+        email -> Varchar,
+        online -> Bool,
+        deliver_at -> Timestamptz,
+        monitor_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    digest_entries (seq) {
+        seq -> Int4,
+        email -> Varchar,
+        node_id -> Varchar,
+        name -> Varchar,
+        online -> Bool,
+        at -> Timestamptz,
+        deliver_at -> Timestamptz,
+        monitor_id -> Varchar,
+    }
+}
+
+diesel::table! {
+    outbox (seq) {
+        seq -> Int4,
+        sender -> Varchar,
+        recipients -> Varchar,
+        message -> Bytea,
+        created_at -> Timestamptz,
+        claimed_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    pending_actions (email, node) {
+        email -> Varchar,
+        node -> Varchar,
+        node_name -> Varchar,
+        action -> Varchar,
+        sent_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    node_history (seq) {
+        seq -> Int4,
+        node_id -> Varchar,
+        online -> Bool,
+        at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    suppressions (email) {
+        email -> Varchar,
+        reason -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    idempotency_keys (key) {
+        key -> Varchar,
+        request_hash -> Varchar,
+        status -> Nullable<Int2>,
+        response -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    notified_states (node_id) {
+        node_id -> Varchar,
+        online -> Bool,
+        notified_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    weekly_summaries (email) {
+        email -> Varchar,
+        deliver_at -> Timestamptz,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(
+    monitors,
+    nodes,
+    queued_notifications,
+    digest_entries,
+    outbox,
+    pending_actions,
+    node_history,
+    suppressions,
+    idempotency_keys,
+    notified_states,
+    weekly_summaries,
+);
@@ -0,0 +1,43 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Addresses that must not get any email, e.g. because they bounced or reported us for spam
+
+use std::collections::HashSet;
+
+use anyhow::Result;
+use diesel::prelude::*;
+
+use crate::db::DbConn;
+use crate::schema::*;
+
+/// Which of the given addresses are suppressed
+pub async fn suppressed(db: &DbConn, emails: Vec<String>) -> Result<HashSet<String>> {
+    let suppressed = db
+        .run(move |db| {
+            suppressions::table
+                .filter(suppressions::email.eq_any(emails))
+                .select(suppressions::email)
+                .load::<String>(db)
+        })
+        .await?;
+    Ok(suppressed.into_iter().collect())
+}
+
+/// Whether the given address is suppressed
+pub async fn is_suppressed(db: &DbConn, email: &str) -> Result<bool> {
+    Ok(!suppressed(db, vec![email.to_owned()]).await?.is_empty())
+}
@@ -0,0 +1,333 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use base64::Engine as _;
+use chrono::Utc;
+use ring::hmac;
+use rmp_serde::from_slice as deserialize_from_slice;
+use rmp_serde::to_vec as serialize_to_vec;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+use crate::util;
+
+const BASE64_ENGINE: base64::engine::GeneralPurpose =
+    base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+/// What a token authorizes.  This is part of the signed data, so that one kind of token cannot be
+/// used in place of another.
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Eq, Debug, Copy, Clone)]
+#[repr(u8)]
+pub enum Purpose {
+    UnsubscribeAll = 1,
+    ListMonitors = 2,
+    /// Submitting a form; see `csrf.rs`
+    Csrf = 3,
+    /// Downloading everything stored about an email address; see `routes::export`
+    ExportData = 4,
+    /// Deleting everything stored about an email address; see `routes::delete_me`
+    DeleteData = 5,
+}
+
+/// The version of the token format, which is part of the signed data.  Tokens from before there
+/// was a version count as version 0; they are signed over just the payload and the expiry time.
+const TOKEN_VERSION: u8 = 1;
+
+/// The minimal length of a signing key, in bytes
+const MIN_KEY_LEN: usize = 32;
+
+/// A key for signing tokens.  We remember its length as `ring` does not tell us.
+pub struct Key {
+    key: hmac::Key,
+    len: usize,
+}
+
+impl Key {
+    pub fn new(bytes: &[u8]) -> Self {
+        Key {
+            key: hmac::Key::new(hmac::HMAC_SHA256, bytes),
+            len: bytes.len(),
+        }
+    }
+}
+
+/// The keys used to sign tokens: new tokens are signed with the primary key, but tokens signed
+/// with one of the old keys are still accepted.
+#[derive(Deserialize)]
+#[serde(try_from = "SigningKeysConfig")]
+pub struct SigningKeys {
+    primary: Key,
+    old: Vec<Key>,
+}
+
+/// The signing keys as they appear in the configuration, where the primary key can also be
+/// read from a file
+#[derive(Deserialize)]
+struct SigningKeysConfig {
+    action_signing_key: Option<String>,
+    action_signing_key_file: Option<PathBuf>,
+    #[serde(
+        rename = "old_action_signing_keys",
+        default,
+        with = "util::hex_signing_keys"
+    )]
+    old: Vec<Key>,
+}
+
+impl TryFrom<SigningKeysConfig> for SigningKeys {
+    type Error = String;
+
+    fn try_from(config: SigningKeysConfig) -> std::result::Result<Self, String> {
+        let (hex_key, source) = match (config.action_signing_key, config.action_signing_key_file) {
+            (Some(key), None) => (key, "`action_signing_key`".to_owned()),
+            (None, Some(path)) => {
+                let key = std::fs::read_to_string(&path).map_err(|e| {
+                    format!(
+                        "cannot read `action_signing_key_file` {}: {}",
+                        path.display(),
+                        e
+                    )
+                })?;
+                // Files usually end in a newline
+                let source = format!("`action_signing_key_file` {}", path.display());
+                (key.trim().to_owned(), source)
+            }
+            (Some(_), Some(_)) => {
+                return Err(
+                    "only one of `action_signing_key` and `action_signing_key_file` may be set"
+                        .to_owned(),
+                )
+            }
+            (None, None) => {
+                return Err(
+                    "missing field `action_signing_key` (or `action_signing_key_file`)".to_owned(),
+                )
+            }
+        };
+        let primary = hex::decode(hex_key)
+            .map_err(|e| format!("{} is not a hex-encoded key: {}", source, e))?;
+        Ok(SigningKeys {
+            primary: Key::new(&primary),
+            old: config.old,
+        })
+    }
+}
+
+impl SigningKeys {
+    /// Describe what is wrong with the keys, if anything
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.primary.len < MIN_KEY_LEN {
+            problems.push(format!(
+                "`action_signing_key` must be at least {} bytes ({} hex digits) long, but is only {}",
+                MIN_KEY_LEN,
+                2 * MIN_KEY_LEN,
+                self.primary.len
+            ));
+        }
+        for (i, key) in self.old.iter().enumerate() {
+            if key.len < MIN_KEY_LEN {
+                problems.push(format!(
+                    "`old_action_signing_keys[{}]` must be at least {} bytes long, but is only {}",
+                    i, MIN_KEY_LEN, key.len
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Sign `payload` such that the token is valid for the given duration
+    pub fn sign<T: Serialize>(&self, payload: T, validity: Duration) -> SignedToken<T> {
+        let expires = Utc::now().timestamp() + validity.as_secs() as i64;
+        let signature = hmac::sign(
+            &self.primary.key,
+            &signed_data(TOKEN_VERSION, &payload, expires),
+        );
+        SignedToken {
+            payload,
+            expires,
+            signature: signature.as_ref().to_vec().into_boxed_slice(),
+            version: TOKEN_VERSION,
+        }
+    }
+
+    /// Derive a key for signing something other than tokens, so that such signatures can never
+    /// be mistaken for token signatures.  The derived key is `HMAC-SHA256(primary key, context)`.
+    pub fn derive(&self, context: &str) -> hmac::Key {
+        let derived = hmac::sign(&self.primary.key, context.as_bytes());
+        hmac::Key::new(hmac::HMAC_SHA256, derived.as_ref())
+    }
+
+    /// Check version, signature and expiry time, and return the payload if all are fine
+    pub fn verify<T: Serialize>(&self, token: SignedToken<T>) -> Result<T> {
+        if token.version > TOKEN_VERSION {
+            bail!("unsupported token version {}", token.version);
+        }
+        let data = signed_data(token.version, &token.payload, token.expires);
+        // `hmac::verify` compares the tags in constant time; never compare them with `==`.
+        if !std::iter::once(&self.primary)
+            .chain(self.old.iter())
+            .any(|key| hmac::verify(&key.key, &data, &token.signature).is_ok())
+        {
+            bail!("signature verification failed");
+        }
+        if Utc::now().timestamp() > token.expires {
+            bail!("token expired");
+        }
+        Ok(token.payload)
+    }
+}
+
+/// A payload together with its expiry time (as a Unix timestamp), and a signature over both and
+/// the version of the token format
+#[derive(Serialize, Deserialize)]
+pub struct SignedToken<T> {
+    payload: T,
+    expires: i64,
+    signature: Box<[u8]>,
+    /// This comes last so that tokens from before there was a version still decode
+    #[serde(default)]
+    version: u8,
+}
+
+/// The data that is signed.  MessagePack encodes structs as arrays of their fields in declaration
+/// order, so encoding the same values always yields the same bytes.
+fn signed_data<T: Serialize>(version: u8, payload: &T, expires: i64) -> Vec<u8> {
+    let data = match version {
+        0 => serialize_to_vec(&(payload, expires)),
+        _ => serialize_to_vec(&(version, payload, expires)),
+    };
+    data.expect("failed to encode token payload")
+}
+
+impl<T: Serialize> SignedToken<T> {
+    /// Encode the token for use in a URL
+    pub fn encode(&self) -> String {
+        BASE64_ENGINE.encode(serialize_to_vec(self).expect("failed to encode token"))
+    }
+}
+
+impl<T: DeserializeOwned> SignedToken<T> {
+    /// Decode a token from its URL representation
+    pub fn decode(s: &str) -> Result<Self> {
+        let bytes = BASE64_ENGINE.decode(s)?;
+        Ok(deserialize_from_slice(&bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALIDITY: Duration = Duration::from_secs(60);
+
+    fn keys(primary: u8, old: &[u8]) -> SigningKeys {
+        SigningKeys {
+            primary: Key::new(&[primary; MIN_KEY_LEN]),
+            old: old.iter().map(|&b| Key::new(&[b; MIN_KEY_LEN])).collect(),
+        }
+    }
+
+    #[test]
+    fn primary_key_verifies() {
+        let keys = keys(1, &[]);
+        let token = keys.sign("payload".to_owned(), VALIDITY);
+        assert_eq!(keys.verify(token).unwrap(), "payload");
+    }
+
+    #[test]
+    fn old_key_verifies() {
+        let token = keys(2, &[]).sign("payload".to_owned(), VALIDITY);
+        let rotated = keys(1, &[3, 2]);
+        assert_eq!(rotated.verify(token).unwrap(), "payload");
+    }
+
+    #[test]
+    fn flipped_tag_bit_is_rejected() {
+        let keys = keys(1, &[]);
+        let mut token = keys.sign("payload".to_owned(), VALIDITY);
+        token.signature[0] ^= 1;
+        assert!(keys.verify(token).is_err());
+    }
+
+    #[test]
+    fn flipped_payload_bit_is_rejected() {
+        let keys = keys(1, &[]);
+        let mut token = keys.sign(vec![0u8; 4], VALIDITY);
+        token.payload[0] ^= 1;
+        assert!(keys.verify(token).is_err());
+    }
+
+    #[test]
+    fn unknown_key_is_rejected() {
+        let token = keys(4, &[]).sign("payload".to_owned(), VALIDITY);
+        assert!(keys(1, &[2, 3]).verify(token).is_err());
+    }
+
+    #[test]
+    fn survives_encoding() {
+        let keys = keys(1, &[]);
+        let token = keys.sign("payload".to_owned(), VALIDITY).encode();
+        let token = SignedToken::<String>::decode(&token).unwrap();
+        assert_eq!(keys.verify(token).unwrap(), "payload");
+    }
+
+    /// A token signed with key `key` for the given format `version`
+    fn versioned_token(key: u8, version: u8) -> SignedToken<String> {
+        let keys = keys(key, &[]);
+        let payload = "payload".to_owned();
+        let expires = Utc::now().timestamp() + VALIDITY.as_secs() as i64;
+        let signature = hmac::sign(&keys.primary.key, &signed_data(version, &payload, expires));
+        SignedToken {
+            payload,
+            expires,
+            signature: signature.as_ref().to_vec().into_boxed_slice(),
+            version,
+        }
+    }
+
+    #[test]
+    fn future_version_is_rejected() {
+        let token = versioned_token(1, TOKEN_VERSION + 1);
+        assert!(keys(1, &[]).verify(token).is_err());
+    }
+
+    #[test]
+    fn unversioned_token_verifies() {
+        /// What tokens looked like before there was a version
+        #[derive(Serialize)]
+        struct UnversionedToken {
+            payload: String,
+            expires: i64,
+            signature: Box<[u8]>,
+        }
+
+        let token = versioned_token(1, 0);
+        let old = UnversionedToken {
+            payload: token.payload,
+            expires: token.expires,
+            signature: token.signature,
+        };
+        let encoded = BASE64_ENGINE.encode(serialize_to_vec(&old).unwrap());
+        let token = SignedToken::<String>::decode(&encoded).unwrap();
+        assert_eq!(token.version, 0);
+        assert_eq!(keys(1, &[]).verify(token).unwrap(), "payload");
+    }
+}
@@ -0,0 +1,173 @@
+//  ff-node-monitor -- Monitoring for Freifunk nodes
+//  Copyright (C) 2018  Ralf Jung <post AT ralfj DOT de>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU Affero General Public License for more details.
+//
+//  You should have received a copy of the GNU Affero General Public License
+//  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Keeping node lists from pointing at internal addresses, so that whoever controls a node list
+//! URL cannot make us request services that are only reachable from this host or network
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use anyhow::{bail, Context as _, Result};
+use reqwest::redirect::Policy;
+use reqwest::ClientBuilder;
+use rocket::tokio;
+use url::{Host, Url};
+
+use crate::config::Config;
+
+/// How many redirects we follow, like reqwest's default policy
+const MAX_REDIRECTS: usize = 10;
+
+/// Whether `ip` is an address on the public internet, as opposed to loopback, private,
+/// link-local, multicast, documentation and similar addresses
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 0.0.0.0/8 is "this network", 100.64.0.0/10 is carrier-grade NAT
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            let embedded = |high: u16, low: u16| {
+                IpAddr::V4(Ipv4Addr::from(u32::from(high) << 16 | u32::from(low)))
+            };
+            // These lead to the IPv4 address they contain: IPv4-mapped (::ffff:0:0/96),
+            // IPv4-compatible (::/96), NAT64 (64:ff9b::/96) and 6to4 (2002::/16)
+            match ip.segments() {
+                [0, 0, 0, 0, 0, 0xffff, high, low]
+                | [0, 0, 0, 0, 0, 0, high, low]
+                | [0x64, 0xff9b, 0, 0, 0, 0, high, low]
+                | [0x2002, high, low, ..] => is_public(embedded(high, low)),
+                // fc00::/7 is unique local, fe80::/10 is link-local, 2001:db8::/32 is for
+                // documentation
+                [first, second, ..] => {
+                    !(ip.is_multicast()
+                        || first & 0xfe00 == 0xfc00
+                        || first & 0xffc0 == 0xfe80
+                        || (first == 0x2001 && second == 0xdb8))
+                }
+            }
+        }
+    }
+}
+
+/// Prepare `builder` for fetching the node list at `url`.  Unless its host is one of the
+/// `allowed_upstream_hosts`, it must resolve to public addresses only, and the client will
+/// connect to exactly those, so that a second DNS lookup cannot lead elsewhere.  Redirects are
+/// only followed to the same host or to an allowed one.
+pub async fn guard(url: &Url, config: &Config, builder: ClientBuilder) -> Result<ClientBuilder> {
+    let allowed = config.ui.get_allowed_upstream_hosts().to_vec();
+    let is_allowed = move |host: &str| allowed.iter().any(|a| a.eq_ignore_ascii_case(host));
+    let Some(host) = url.host() else {
+        bail!("node list URL {} has no host", url);
+    };
+    let host_name = host.to_string();
+    let redirect = Policy::custom({
+        let host_name = host_name.clone();
+        let is_allowed = is_allowed.clone();
+        move |attempt| {
+            if attempt.previous().len() > MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            match attempt.url().host_str() {
+                Some(to) if to.eq_ignore_ascii_case(&host_name) || is_allowed(to) => {
+                    attempt.follow()
+                }
+                _ => attempt.error("node list redirects to another host"),
+            }
+        }
+    });
+    let builder = builder.redirect(redirect);
+    if is_allowed(&host_name) {
+        return Ok(builder);
+    }
+
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match host {
+        Host::Ipv4(ip) => vec![SocketAddr::new(ip.into(), port)],
+        Host::Ipv6(ip) => vec![SocketAddr::new(ip.into(), port)],
+        Host::Domain(domain) => tokio::net::lookup_host((domain, port))
+            .await
+            .with_context(|| format!("failed to resolve {}", domain))?
+            .collect(),
+    };
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        bail!(
+            "node list {} is at the internal address {}; add `{}` to `allowed_upstream_hosts` \
+             to fetch it anyway",
+            url,
+            addr.ip(),
+            host_name
+        );
+    }
+    Ok(match host {
+        Host::Domain(domain) => builder.resolve_to_addrs(domain, &addrs),
+        _ => builder,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_addresses() {
+        for (ip, public) in [
+            ("93.184.216.34", true),
+            ("100.63.255.255", true),
+            ("100.128.0.1", true),
+            ("10.0.0.1", false),
+            ("172.16.0.1", false),
+            ("192.168.1.1", false),
+            ("127.0.0.1", false),
+            ("169.254.169.254", false),
+            ("0.0.0.0", false),
+            ("0.1.2.3", false),
+            ("100.64.0.1", false),
+            ("255.255.255.255", false),
+            ("224.0.0.1", false),
+            ("192.0.2.1", false),
+            ("2606:2800:220:1:248:1893:25c8:1946", true),
+            ("::ffff:93.184.216.34", true),
+            ("64:ff9b::5db8:d822", true),
+            ("2002:5db8:d822::1", true),
+            ("::1", false),
+            ("::", false),
+            ("fc00::1", false),
+            ("fd12:3456::1", false),
+            ("fe80::1", false),
+            ("ff02::1", false),
+            ("2001:db8::1", false),
+            ("::ffff:10.0.0.1", false),
+            ("::ffff:127.0.0.1", false),
+            ("::10.0.0.1", false),
+            ("::127.0.0.1", false),
+            ("64:ff9b::a00:1", false),
+            ("64:ff9b::7f00:1", false),
+            ("2002:a00:1::1", false),
+            ("2002:7f00:1::", false),
+        ] {
+            assert_eq!(is_public(ip.parse().unwrap()), public, "{}", ip);
+        }
+    }
+}
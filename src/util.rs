@@ -15,41 +15,352 @@
 //  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::Result;
 
+use ring::rand::{SecureRandom, SystemRandom};
 use rocket::{
+    fairing::{AdHoc, Fairing, Info, Kind},
     request::{self, FromRequest, Outcome},
-    Request,
+    tokio::{self, task::JoinHandle},
+    Data, Request, Response,
 };
 use rocket_dyn_templates::Template;
 
 use crate::config::Config;
+use crate::helpers;
 
-/// Module for serde "with" to use hex encoding to byte arrays
-pub mod hex_signing_key {
+/// Module for serde "with" to use hex encoding to a list of byte arrays
+pub mod hex_signing_keys {
     use hex;
-    use ring::hmac;
     use serde::{de::Error, Deserialize, Deserializer};
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<hmac::Key, D::Error>
+    use crate::token::Key;
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Key>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let bytes = hex::decode(String::deserialize(deserializer)?).map_err(Error::custom)?;
-        Ok(hmac::Key::new(hmac::HMAC_SHA256, bytes.as_slice()))
+        Vec::<String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|key| {
+                let bytes = hex::decode(key).map_err(Error::custom)?;
+                Ok(Key::new(&bytes))
+            })
+            .collect()
+    }
+}
+
+/// An ID identifying a request in the logs
+#[derive(Clone, Copy)]
+pub struct RequestId(u32);
+
+impl RequestId {
+    fn new() -> Self {
+        let mut bytes = [0u8; 4];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .expect("failed to generate random number");
+        RequestId(u32::from_le_bytes(bytes))
+    }
+
+    /// The ID of the given request
+    pub fn of(request: &Request<'_>) -> Self {
+        *request.local_cache(RequestId::new)
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
     }
 }
 
+/// The IP address of the client, as a request guard.
+///
+/// If the direct peer is one of the `trusted_proxies`, the address is taken from the
+/// `X-Forwarded-For` header: it is the right-most address there that is not a trusted proxy
+/// itself.  Without `trusted_proxies`, this is whatever Rocket considers the client IP.
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    /// The client IP of the given request
+    pub fn of(request: &Request<'_>) -> Self {
+        *request.local_cache(|| ClientIp(Self::compute(request)))
+    }
+
+    fn compute(request: &Request<'_>) -> Option<IpAddr> {
+        let trusted = request
+            .rocket()
+            .state::<Config>()
+            .map(|config| config.ui.get_trusted_proxies())
+            .unwrap_or_default();
+        if trusted.is_empty() {
+            return request.client_ip();
+        }
+        let peer = request.remote()?.ip();
+        if !trusted.contains(&peer) {
+            // Anyone can send that header, so only believe it if a proxy we know set it
+            return Some(peer);
+        }
+        // Every proxy appends the address it got the request from, so walk the list backwards
+        // until we leave the proxies we trust.  If the list is garbage from some point on, the
+        // last trustworthy hop is the best we know.
+        let forwarded: Vec<&str> = request
+            .headers()
+            .get("X-Forwarded-For")
+            .flat_map(|header| header.split(','))
+            .collect();
+        let mut client = peer;
+        for hop in forwarded.into_iter().rev() {
+            let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = hop;
+            if !trusted.contains(&hop) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = std::convert::Infallible;
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(ClientIp::of(request))
+    }
+}
+
+/// Whether the request is a declared prefetch or link preview, as a request guard.  Such requests
+/// are made without the user asking for it, so they must not change anything.
+#[derive(Clone, Copy)]
+pub struct Prefetch(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Prefetch {
+    type Error = std::convert::Infallible;
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let prefetch = ["Sec-Purpose", "Purpose", "X-Purpose", "X-Moz"]
+            .iter()
+            .flat_map(|name| headers.get(name))
+            .any(|value| {
+                let value = value.to_ascii_lowercase();
+                value.contains("prefetch") || value.contains("preview")
+            });
+        Outcome::Success(Prefetch(prefetch))
+    }
+}
+
+/// The language the client prefers according to its `Accept-Language` header, among the
+/// `languages` we offer, as a request guard.  `None` if it accepts none of them.
+#[derive(Clone)]
+pub struct PreferredLang(pub Option<String>);
+
+impl PreferredLang {
+    fn compute(request: &Request<'_>) -> Option<String> {
+        let languages = request.rocket().state::<Config>()?.ui.get_languages();
+        // `tag;q=weight`, where the weight defaults to 1 and 0 means "not acceptable"
+        let mut ranges: Vec<(f32, &str)> = request
+            .headers()
+            .get("Accept-Language")
+            .flat_map(|header| header.split(','))
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let weight = match parts.find_map(|p| p.trim().strip_prefix("q=")) {
+                    Some(weight) => weight.parse().ok()?,
+                    None => 1.0,
+                };
+                (!tag.is_empty() && weight > 0.0).then_some((weight, tag))
+            })
+            .collect();
+        // Stable, so that ranges of the same weight keep their order
+        ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranges.into_iter().find_map(|(_, tag)| {
+            // `de-AT` is fine for `de`
+            let primary = tag.split('-').next().unwrap_or(tag);
+            languages
+                .iter()
+                .find(|lang| lang.eq_ignore_ascii_case(tag) || lang.eq_ignore_ascii_case(primary))
+                .map(|lang| lang.to_string())
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PreferredLang {
+    type Error = std::convert::Infallible;
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(PreferredLang(Self::compute(request)))
+    }
+}
+
+/// A fairing that logs every request together with its ID and client, and reports the ID to the
+/// client in the `X-Request-Id` header.
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let client = match ClientIp::of(request).0 {
+            Some(ip) => ip.to_string(),
+            None => "unknown client".to_owned(),
+        };
+        log::info!(
+            "[{}] {} {} from {}",
+            RequestId::of(request),
+            request.method(),
+            request.uri(),
+            client
+        );
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_raw_header("X-Request-Id", RequestId::of(request).to_string());
+    }
+}
+
+/// Managed state keeping track of tasks that outlive the request that spawned them, so that
+/// shutdown can wait for them to finish.
+#[derive(Default)]
+pub struct BackgroundTasks(Mutex<Vec<JoinHandle<()>>>);
+
+impl BackgroundTasks {
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.0.lock().unwrap();
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(tokio::spawn(task));
+    }
+
+    /// A fairing that, on shutdown, waits for the background tasks to finish.  They get as much
+    /// time as requests do, i.e. the configured grace period; tasks still running after that are
+    /// aborted.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::on_shutdown("Finish background tasks", |rocket| {
+            Box::pin(async move {
+                let tasks = std::mem::take(&mut *rocket.state::<Self>().unwrap().0.lock().unwrap());
+                let grace = Duration::from_secs(rocket.config().shutdown.grace.into());
+                let aborts: Vec<_> = tasks.iter().map(|task| task.abort_handle()).collect();
+                let all_done = async {
+                    for task in tasks {
+                        let _ = task.await;
+                    }
+                };
+                if tokio::time::timeout(grace, all_done).await.is_err() {
+                    let unfinished = aborts.iter().filter(|task| !task.is_finished()).count();
+                    log::warn!("aborting {} unfinished background tasks", unfinished);
+                    for task in aborts {
+                        task.abort();
+                    }
+                }
+            })
+        })
+    }
+}
+
+/// The URL under which the running instance itself reaches `uri`.  Background tasks that need
+/// the instance request such URLs, just like an external cron job requests `/cron`.
+pub fn local_url(rocket: &rocket::Rocket<rocket::Orbit>, uri: impl fmt::Display) -> String {
+    let address = match rocket.config().address {
+        IpAddr::V4(a) if a.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(a) if a.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        a => a,
+    };
+    format!(
+        "http://{}{}",
+        SocketAddr::new(address, rocket.config().port),
+        uri
+    )
+}
+
+/// The templates we render.  Translations and the `node_up`/`node_down` variants of
+/// `notification` are optional.
+const TEMPLATES: &[&str] = &[
+    "confirm_action",
+    "cron",
+    "cron_error",
+    "data_deleted",
+    "delete_me",
+    "digest",
+    "index",
+    "list",
+    "list_error",
+    "maintenance",
+    "notification",
+    "notification_matrix",
+    "notifications",
+    "prepare_action",
+    "prepare_action_error",
+    "resend",
+    "run_action",
+    "run_action_confirm",
+    "run_action_error",
+    "stale_alert",
+    "test_email",
+    "unsubscribe_all",
+    "weekly_summary",
+];
+
+/// A fairing that sets up the templates.  Templates that fail to parse already abort the launch;
+/// this also aborts it if one of the templates we need is missing, so that a broken template
+/// directory is noticed on deployment and not when the first email is due.
+pub fn templates() -> impl Fairing {
+    AdHoc::on_ignite("Templates", |rocket| async {
+        let names = Arc::new(TemplateNames::default());
+        rocket
+            .manage(names.clone())
+            .attach(Template::try_custom(move |engines| {
+                engines.handlebars.set_strict_mode(true);
+                helpers::register(&mut engines.handlebars);
+                let missing: Vec<&str> = TEMPLATES
+                    .iter()
+                    .copied()
+                    .filter(|name| !engines.handlebars.has_template(name))
+                    .collect();
+                if !missing.is_empty() {
+                    return Err(format!("missing templates: {}", missing.join(", ")).into());
+                }
+                // This runs again whenever the templates get reloaded
+                *names.0.lock().unwrap() =
+                    engines.handlebars.get_templates().keys().cloned().collect();
+                Ok(())
+            }))
+    })
+}
+
+/// Managed state holding the names of the templates that were loaded
+#[derive(Default)]
+struct TemplateNames(Mutex<HashSet<String>>);
+
 /// A request guard to get access to the rocket.
-pub struct Ctx<'r>(&'r rocket::Rocket<rocket::Orbit>);
+pub struct Ctx<'r> {
+    rocket: &'r rocket::Rocket<rocket::Orbit>,
+    request_id: RequestId,
+}
 
 impl Deref for Ctx<'_> {
     type Target = rocket::Rocket<rocket::Orbit>;
 
     fn deref(&self) -> &Self::Target {
-        self.0
+        self.rocket
     }
 }
 
@@ -57,15 +368,31 @@ impl Deref for Ctx<'_> {
 impl<'r> FromRequest<'r> for Ctx<'r> {
     type Error = ();
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        Outcome::Success(Ctx(request.rocket()))
+        Outcome::Success(Ctx {
+            rocket: request.rocket(),
+            request_id: RequestId::of(request),
+        })
     }
 }
 
-impl Ctx<'_> {
+impl<'r> Ctx<'r> {
+    /// Get a context outside of a request
+    pub fn new(rocket: &'r rocket::Rocket<rocket::Orbit>) -> Self {
+        Ctx {
+            rocket,
+            request_id: RequestId::new(),
+        }
+    }
+
     pub fn config(&self) -> &Config {
         self.state::<Config>().unwrap()
     }
 
+    /// The ID of the current request, to be included in log lines
+    pub fn request_id(&self) -> RequestId {
+        self.request_id
+    }
+
     pub fn template(
         &self,
         name: impl Into<Cow<'static, str>>,
@@ -73,4 +400,63 @@ impl Ctx<'_> {
     ) -> Result<Template> {
         Ok(Template::render(name, self.config().template_vals(vals)?))
     }
+
+    /// Whether a template with the given name was loaded
+    pub fn has_template(&self, name: &str) -> bool {
+        let names = self.state::<Arc<TemplateNames>>().unwrap();
+        let names = names.0.lock().unwrap();
+        names.contains(name)
+    }
+
+    /// The best template with the given name for the given language: `name.lang` if it exists,
+    /// then the same for the default language, and finally `name` itself.  Returns `None` if
+    /// none of them exists.
+    pub fn find_template(&self, name: &str, lang: Option<&str>) -> Option<String> {
+        let default_lang = self.config().ui.get_default_lang();
+        lang.into_iter()
+            .chain([default_lang])
+            .map(|lang| format!("{}.{}", name, lang))
+            .chain([name.to_owned()])
+            .find(|template| self.has_template(template))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::{get, routes};
+
+    use super::*;
+
+    #[get("/")]
+    fn prefetch(prefetch: Prefetch) -> &'static str {
+        if prefetch.0 {
+            "prefetch"
+        } else {
+            "visit"
+        }
+    }
+
+    #[rocket::async_test]
+    async fn prefetch_headers() {
+        let rocket = rocket::custom(rocket::Config::debug_default()).mount("/", routes![prefetch]);
+        let client = Client::tracked(rocket).await.unwrap();
+        for (header, expected) in [
+            (Some(("Sec-Purpose", "prefetch")), "prefetch"),
+            (Some(("Sec-Purpose", "prefetch;prerender")), "prefetch"),
+            (Some(("Purpose", "prefetch")), "prefetch"),
+            (Some(("X-Purpose", "preview")), "prefetch"),
+            (Some(("X-Moz", "prefetch")), "prefetch"),
+            (Some(("Purpose", "something else")), "visit"),
+            (None, "visit"),
+        ] {
+            let mut request = client.get("/");
+            if let Some((name, value)) = header {
+                request = request.header(Header::new(name, value));
+            }
+            let body = request.dispatch().await.into_string().await.unwrap();
+            assert_eq!(body, expected, "{:?}", header);
+        }
+    }
 }